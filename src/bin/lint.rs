@@ -0,0 +1,27 @@
+use rustic_raycaster::{lint::{lint, Severity}, loader::LoadOptions};
+use anyhow::{Context, Result};
+use std::io::BufReader;
+
+fn main() -> Result<()> {
+
+    let args: Vec<_> = std::env::args().collect();
+
+    if args.len() != 2 {
+        eprintln!("Usage: lint <MAP FILE>");
+        std::process::exit(1);
+    }
+
+    let fh = std::fs::File::open(&args[1]).with_context(|| format!("opening {}", args[1]))?;
+    let issues = lint(BufReader::new(fh), &LoadOptions::default())?;
+
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    if issues.iter().any(|issue| issue.severity == Severity::Error) {
+        std::process::exit(1);
+    }
+
+    Ok(())
+
+}
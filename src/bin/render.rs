@@ -1,16 +1,49 @@
-use anyhow::{Result,Context};
-use rustic_raycaster::{geometry::Vector, loader::Map, render::Render};
+use anyhow::{anyhow, bail, Result, Context};
+use rustic_raycaster::{geometry::{v, Vector}, loader::Map, render::Render};
 use std::{io::BufReader, time::Instant};
 
+const USAGE: &str = "Usage: render [--pos X,Y] [--angle DEGREES] <CUB FILE> <OUTPUT FILE>";
+
+/// Reads an `X,Y` camera position.
+fn parse_pos(s: &str) -> Result<Vector> {
+    let xy: Vec<_> = s.split(',').collect();
+    if xy.len() != 2 {
+        bail!("--pos: X,Y expected, got {}\n{}", s, USAGE);
+    }
+    let (x, y): (f64, f64) = (xy[0].trim().parse()?, xy[1].trim().parse()?);
+    if !x.is_finite() || !y.is_finite() {
+        bail!("--pos: finite X,Y expected, got {}\n{}", s, USAGE);
+    }
+    Ok(v(x, y))
+}
+
+/// Reads a camera angle in degrees.
+fn parse_angle(s: &str) -> Result<f64> {
+    match s.trim().parse::<f64>() {
+        Ok(degrees) if degrees.is_finite() => Ok(degrees),
+        _ => bail!("--angle: finite degrees expected, got {}\n{}", s, USAGE),
+    }
+}
+
 fn main() -> Result<()> {
-    let args: Vec<_> = std::env::args().collect();
+    let mut args = std::env::args().skip(1);
+    let mut files = Vec::new();
+    let (mut pos, mut angle) = (None, None);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--pos" => pos = Some(parse_pos(&args.next().ok_or(anyhow!("--pos needs a value"))?)?),
+            "--angle" => angle = Some(parse_angle(&args.next().ok_or(anyhow!("--angle needs a value"))?)?),
+            _ => files.push(arg),
+        }
+    }
 
-    if args.len() != 3 {
-        eprintln!("Usage: render <CUB FILE> <OUTPUT FILE>");
+    if files.len() != 2 {
+        eprintln!("{}", USAGE);
         std::process::exit(1);
     }
 
-    let fh = std::fs::File::open(&args[1])?;
+    let fh = std::fs::File::open(&files[0])?;
     let buf = BufReader::new(fh);
 
     let t0 = Instant::now();
@@ -19,14 +52,22 @@ fn main() -> Result<()> {
     let t1 = Instant::now();
 
     let mut r = Render::spawn(&map);
-    //r.cam = Vector::angle(265.0f64.to_radians());
+    if let Some(pos) = pos {
+        if pos.x() < 0.0 || pos.y() < 0.0 || !map.is_walkable(pos.x() as usize, pos.y() as usize) {
+            bail!("--pos {},{} is not a walkable cell of the map", pos.x(), pos.y());
+        }
+        r.pos = pos;
+    }
+    if let Some(degrees) = angle {
+        r.cam = Vector::angle(degrees.to_radians());
+    }
     r.render(&map);
     let t2 = Instant::now();
 
     eprintln!("Loaded in {:?}, rendered in {:?}", t1-t0, t2-t1);
 
-    r.buffer.save(&args[2])?;
+    r.buffer.save(&files[1])?;
 
     Ok(())
 
-}
\ No newline at end of file
+}
@@ -1,8 +1,9 @@
 use {
     std::{
-        iter::{Peekable, Rev},
+        iter::Rev,
         ops::Range,
         ops::Add,
+        ops::Sub,
         ops::Mul
     },
     either::Either,
@@ -27,10 +28,34 @@ impl Vector {
         Vector { x: theta.cos(), y: theta.sin() }
     }
 
+    pub fn x(self) -> f64 {
+        self.x
+    }
+
+    pub fn y(self) -> f64 {
+        self.y
+    }
+
+    /// The angle of this vector from the x axis, in `[-π, π]`.
+    pub fn heading(self) -> f64 {
+        self.y.atan2(self.x)
+    }
+
+    pub fn dot(self, rhs: Self) -> f64 {
+        self.x*rhs.x + self.y*rhs.y
+    }
+
     pub fn squared_norm(self) -> f64 {
         self.x*self.x + self.y*self.y
     }
 
+    /// The point a fraction `t` of the way from `self` to `other`, for
+    /// interpolating positions. Interpolating directions this way doesn't
+    /// keep them unit length; interpolate their `heading` instead.
+    pub fn lerp(self, other: Self, t: f64) -> Self {
+        self * (1.0 - t) + other * t
+    }
+
     pub fn squared_distance(&self, rhs: &Self) -> f64 {
         let dx = rhs.x - self.x;
         let dy = rhs.y - self.y;
@@ -47,6 +72,15 @@ impl Add for Vector {
     }
 }
 
+impl Sub for Vector {
+    type Output = Vector;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Vector { x: self.x - rhs.x,
+                 y: self.y - rhs.y }
+    }
+}
+
 impl Mul<f64> for Vector {
     type Output = Vector;
 
@@ -70,6 +104,18 @@ impl Grid {
         (0..self.height).contains(&y) &&
         (0..self.width).contains(&x)
     }
+
+    /// Every `(x, y)` cell the semi-line from `from` along `dir` passes
+    /// through, in order, starting with the cell of `from`. Like `Raycaster`,
+    /// this expects `from` inside the grid. A ray through a cell corner goes
+    /// straight to the diagonal cell.
+    pub fn cells_along(&self, from: Vector, dir: Vector) -> impl Iterator<Item = (usize, usize)> {
+        let caster = Raycaster::new(from, dir, *self);
+        let mut last = None;
+        caster.cell.into_iter()
+            .chain(caster.map(|hit| (hit.x, hit.y)))
+            .filter(move |&cell| last.replace(cell) != Some(cell))
+    }
 }
 
 type Position = Vector;
@@ -86,30 +132,38 @@ pub struct Hit {
     pub y: usize,
     pub direction: Direction,
     pub position: f64,
-    pub distance: f64,
+    pub squared_distance: f64,
+}
+
+impl Hit {
+    /// The Euclidean distance from the camera to the hit.
+    pub fn distance(&self) -> f64 {
+        self.squared_distance.sqrt()
+    }
 }
 
 struct Interceptor {
     p: Position,
     slope: f64,
     range: DynRange,
+    peeked: Option<Option<(usize,f64,Position)>>,
 }
 
 /// An iterator over all integers in the interval between start and either 0 or `size`,
 /// depending on the sign of `direction`. This is meant to compute all potentially 
-/// intersecting grid lines for a single axis.
+/// intersecting grid lines for a single axis. A start exactly on a grid line
+/// excludes that line: the ray is already past it. A null direction never
+/// crosses any line.
 fn bounded_iterator(start: f64, direction: f64, size: usize) -> DynRange {
     
-    if size == 0 || start < 0.0 {
-        return Either::Left(1..0); // empty range
+    if size == 0 || start < 0.0 || direction == 0.0 {
+        return Either::Left(0..0); // empty range
     }
     
-    let ceil = start.ceil() as usize;
-
     if direction < 0.0 {
-            Either::Right((1..ceil).rev())
+            Either::Right((1..start.ceil() as usize).rev())
     } else {
-            Either::Left(ceil .. size)
+            Either::Left(start.floor() as usize + 1 .. size)
     }
 }
 
@@ -122,39 +176,232 @@ impl Interceptor {
         let range = bounded_iterator(p.x, d.x, size);
         let slope = d.y / d.x;
 
-        Self { p, slope, range }
+        Self { p, slope, range, peeked: None }
     }
-}
 
-impl Iterator for Interceptor {
-    type Item = (usize,f64,Position);
+    /// Restarts the interceptor in place on a new semi-line, as if built
+    /// by `new`.
+    fn reset(&mut self, p: Position, d: Vector, size: usize) {
+        self.p = p;
+        self.slope = d.y / d.x;
+        self.range = bounded_iterator(p.x, d.x, size);
+        self.peeked = None;
+    }
 
-    fn next(&mut self) -> Option<(usize,f64,Position)> {
+    /// The next intersection, without consuming it.
+    fn peek(&mut self) -> Option<&(usize,f64,Position)> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.intersect());
+        }
+        self.peeked.as_ref().and_then(Option::as_ref)
+    }
+
+    fn intersect(&mut self) -> Option<(usize,f64,Position)> {
         self.range
             .next()
-            .and_then(|xi| {
+            .map(|xi| {
                 let x = xi as f64;
                 let y = self.p.y + (x - self.p.x) * self.slope;
                 let v = Vector{x,y};
-                Some((xi, v.squared_distance(&self.p), v))               
+                (xi, v.squared_distance(&self.p), v)
             })
     }
 }
 
+impl Iterator for Interceptor {
+    type Item = (usize,f64,Position);
+
+    fn next(&mut self) -> Option<(usize,f64,Position)> {
+        match self.peeked.take() {
+            Some(peeked) => peeked,
+            None => self.intersect(),
+        }
+    }
+}
+
 // An iterator of all the wall hits for a given position, direction and grid size
 pub struct Raycaster {
     g: Grid,
+    p: Position,
     d: Vector,
-    xint: Peekable<Interceptor>,
-    yint: Peekable<Interceptor>,
+    cell: Option<(usize,usize)>,
+    xint: Interceptor,
+    yint: Interceptor,
+    wrap: bool,
+    wraps: usize,
+    traveled: f64,
+    cutoff: f64,
+}
+
+/// Whether `d` gives no direction to follow: null, too small to square, or
+/// not finite. Rays along such directions hit nothing.
+fn degenerate(d: Vector) -> bool {
+    !(d.x.is_finite() && d.y.is_finite()) || d.squared_norm() == 0.0
+}
+
+/// The cell a ray from `p` along `d` starts in: on a grid line, the cell it
+/// moves into. Degenerate rays are in no cell.
+fn start_cell(p: Position, d: Vector, g: Grid) -> Option<(usize,usize)> {
+    if degenerate(d) {
+        return None;
+    }
+    let start = |p: f64, d: f64| if d < 0.0 && p.fract() == 0.0 { p - 1.0 } else { p };
+    let (x, y) = (start(p.x, d.x), start(p.y, d.y));
+    if x >= 0.0 && y >= 0.0 && g.contains(x as usize, y as usize) {
+        Some((x as usize, y as usize))
+    } else {
+        None
+    }
 }
 
+/// How many times a wrapping raycaster may cross the grid edge before it
+/// gives up, so that a ray down an open corridor of a toroidal map ends.
+pub const MAX_WRAPS: usize = 64;
+
 impl Raycaster {
+    /// A ray starting exactly on a grid line starts in the cell it moves
+    /// into, and its first hit is the next line along, never the one it
+    /// starts on.
     pub fn new(p: Position, d: Vector, g: Grid)  -> Self {
+        let (width, height) = if degenerate(d) { (0, 0) } else { (g.width, g.height) };
         Raycaster {
-            g,d,
-            xint: Interceptor::new(p,d,g.width).peekable(),
-            yint: Interceptor::new(p.flip(), d.flip(), g.height).peekable(),
+            g,p,d,
+            cell: start_cell(p, d, g),
+            xint: Interceptor::new(p,d,width),
+            yint: Interceptor::new(p.flip(), d.flip(), height),
+            wrap: false,
+            wraps: 0,
+            traveled: 0.0,
+            cutoff: f64::INFINITY,
+        }
+    }
+
+    /// Ends the ray before the first hit at least `squared_distance` away,
+    /// rather than at the edge of the grid.
+    pub fn with_cutoff(mut self, squared_distance: f64) -> Self {
+        self.cutoff = squared_distance;
+        self
+    }
+
+    /// Makes the ray re-enter the grid from the opposite edge when it would
+    /// leave it, as on a toroidal map. Hit distances keep counting from the
+    /// original position.
+    pub fn wrapping(mut self) -> Self {
+        self.wrap = true;
+        self
+    }
+
+    /// Restarts the raycaster on a new ray, in place, so that a renderer can
+    /// keep a single raycaster across screen columns.
+    pub fn reset(&mut self, p: Position, d: Vector, g: Grid) {
+        self.g = g;
+        self.p = p;
+        self.d = d;
+        self.cell = start_cell(p, d, g);
+        let (width, height) = if degenerate(d) { (0, 0) } else { (g.width, g.height) };
+        self.xint.reset(p, d, width);
+        self.yint.reset(p.flip(), d.flip(), height);
+        self.wraps = 0;
+        self.traveled = 0.0;
+    }
+
+    // Moves the ray origin from the point where it leaves the grid to the
+    // matching point on the opposite edge, and reports the cell it enters.
+    fn wrap_around(&mut self) -> Option<Hit> {
+        let (x, y, face) = self.exit()?;
+        let t = match face {
+            Direction::E => (x as f64 + 1.0 - self.p.x) / self.d.x,
+            Direction::W => (x as f64 - self.p.x) / self.d.x,
+            Direction::S => (y as f64 + 1.0 - self.p.y) / self.d.y,
+            Direction::N => (y as f64 - self.p.y) / self.d.y,
+        };
+        let q = self.p + self.d * t;
+        let (w, h) = (self.g.width as f64, self.g.height as f64);
+
+        let (p, (x, y), direction, position) = match face {
+            Direction::E => (v(0.0, q.y), (0, y), Direction::W, q.y - q.y.floor()),
+            Direction::W => (v(w, q.y), (self.g.width - 1, y), Direction::E, q.y - q.y.floor()),
+            Direction::S => (v(q.x, 0.0), (x, 0), Direction::N, q.x - q.x.floor()),
+            Direction::N => (v(q.x, h), (x, self.g.height - 1), Direction::S, q.x - q.x.floor()),
+        };
+
+        self.traveled += t * self.d.squared_norm().sqrt();
+        self.wraps += 1;
+        self.p = p;
+        self.cell = Some((x, y));
+        self.xint.reset(p, self.d, self.g.width);
+        self.yint.reset(p.flip(), self.d.flip(), self.g.height);
+
+        Some(Hit { x, y, position, direction, squared_distance: self.traveled * self.traveled })
+    }
+
+    /// The last grid cell the ray has reached so far, and the face through
+    /// which it leaves that cell. Once the iterator is exhausted, this is the
+    /// cell and face through which the ray exits the grid.
+    pub fn exit(&self) -> Option<(usize, usize, Direction)> {
+        let (x,y) = self.cell?;
+
+        let tx = if self.d.x > 0.0 {
+            (x as f64 + 1.0 - self.p.x) / self.d.x
+        } else if self.d.x < 0.0 {
+            (x as f64 - self.p.x) / self.d.x
+        } else {
+            f64::INFINITY
+        };
+
+        let ty = if self.d.y > 0.0 {
+            (y as f64 + 1.0 - self.p.y) / self.d.y
+        } else if self.d.y < 0.0 {
+            (y as f64 - self.p.y) / self.d.y
+        } else {
+            f64::INFINITY
+        };
+
+        let direction = if tx <= ty {
+            if self.d.x < 0.0 { Direction::W } else { Direction::E }
+        } else if self.d.y < 0.0 {
+            Direction::N
+        } else {
+            Direction::S
+        };
+
+        Some((x, y, direction))
+    }
+
+    /// Turns this raycaster into an iterator that yields every wall hit,
+    /// followed by a final `RaycastOutcome::Exit` when the ray leaves the grid.
+    pub fn outcomes(self) -> Outcomes {
+        Outcomes { inner: self, done: false }
+    }
+}
+
+/// Either a hit on a cell face, or the point where a ray leaves the grid.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RaycastOutcome {
+    Hit(Hit),
+    Exit { x: usize, y: usize, direction: Direction },
+}
+
+/// An iterator over the hits of a `Raycaster`, terminated by its grid exit.
+pub struct Outcomes {
+    inner: Raycaster,
+    done: bool,
+}
+
+impl Iterator for Outcomes {
+    type Item = RaycastOutcome;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.inner.next() {
+            Some(hit) => Some(RaycastOutcome::Hit(hit)),
+            None => {
+                self.done = true;
+                self.inner.exit().map(|(x, y, direction)| RaycastOutcome::Exit { x, y, direction })
+            }
         }
     }
 }
@@ -163,6 +410,25 @@ impl Iterator for Raycaster {
     type Item = Hit;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let hit = match self.step() {
+            Some(mut hit) => {
+                if self.wraps > 0 {
+                    hit.squared_distance = (hit.squared_distance.sqrt() + self.traveled).powi(2);
+                }
+                hit
+            }
+            None if self.wrap && self.wraps < MAX_WRAPS => self.wrap_around()?,
+            None => return None,
+        };
+
+        if hit.squared_distance < self.cutoff { Some(hit) } else { None }
+    }
+}
+
+impl Raycaster {
+    // The next hit of the current, unwrapped ray, with distances measured
+    // from its origin.
+    fn step(&mut self) -> Option<Hit> {
 
         let px = self.xint.peek();
         let py = self.yint.peek();
@@ -177,16 +443,19 @@ impl Iterator for Raycaster {
             (None,Some(_)) => false,
         };
 
-        let (x,y,position,direction, distance);
+        let (x,y,position,direction, squared_distance);
 
         
         if xhit {
             let (xi,d, p) = self.xint.next().unwrap();
+            if p.y < 0.0 {
+                return None;
+            }
             let fy = p.y.floor();
             
             y = fy as usize;
             position = p.y - fy;
-            distance = d;
+            squared_distance = d;
             if self.d.x < 0.0 {
                 x = xi - 1; 
                 direction = Direction::E 
@@ -198,11 +467,14 @@ impl Iterator for Raycaster {
         } else {
             let (yi,d, p) = self.yint.next().unwrap();
             let p = p.flip();
+            if p.x < 0.0 {
+                return None;
+            }
             let fx = p.x.floor();
             
             x = fx as usize;
             position = p.x - fx;
-            distance = d;
+            squared_distance = d;
             if self.d.y < 0.0 { 
                 y = yi - 1;
                 direction = Direction::S
@@ -213,11 +485,12 @@ impl Iterator for Raycaster {
         
         }
 
-        if !self.g.contains(x,y) || distance.is_infinite() {
+        if !self.g.contains(x,y) || squared_distance.is_infinite() {
             return None;
         }
  
-        Some(Hit {x,y,position,direction,distance})
+        self.cell = Some((x,y));
+        Some(Hit {x,y,position,direction,squared_distance})
 
     }
 }
@@ -242,6 +515,18 @@ fn test_interceptor() {
 
 }
 
+#[test]
+fn test_interceptor_reset() {
+    let mut int = Interceptor::new(v(0.5, 1.5), v(2.0, 1.0), 6);
+    int.next();
+    int.peek();
+    for &(start, dir) in [(v(4.5, 0.5), v(-1.0, 3.0)), (v(2.0, 2.0), v(1.0, -1.0)), (v(0.5, 1.5), v(2.0, 1.0))].iter() {
+        int.reset(start, dir, 6);
+        let reset: Vec<_> = int.by_ref().collect();
+        assert_eq!(reset, Interceptor::new(start, dir, 6).collect::<Vec<_>>());
+    }
+}
+
 #[test]
 fn test_raycaster() { 
 
@@ -250,22 +535,162 @@ fn test_raycaster() {
 
     let hits: Vec<_> = Raycaster::new(middle ,  v(0.0, -1.0) , grid).take(2).collect();
     let expected = vec![
-        Hit { x: 2, y: 1, direction: Direction::S, position: 0.5, distance: 0.25 },
-        Hit { x: 2, y: 0, direction: Direction::S, position: 0.5, distance: 2.25 },
+        Hit { x: 2, y: 1, direction: Direction::S, position: 0.5, squared_distance: 0.25 },
+        Hit { x: 2, y: 0, direction: Direction::S, position: 0.5, squared_distance: 2.25 },
     ];
 
     assert_eq!(hits, expected);
     
     let hits: Vec<_> = Raycaster::new(v(0.5, 1.5) ,  v(2.0, 1.0) , grid).collect();
     let expected = vec![
-        Hit { x: 1, y: 1, direction: Direction::W, position: 0.75, distance: 5.0/16.0 },
-        Hit { x: 1, y: 2, direction: Direction::N, position: 0.5, distance: 5.0/4.0 },
-        Hit { x: 2, y: 2, direction: Direction::W, position: 0.25, distance: 45.0/16.0 },
-        Hit { x: 3, y: 2, direction: Direction::W, position: 0.75, distance: 125.0/16.0 },
-        Hit { x: 3, y: 3, direction: Direction::N, position: 0.5, distance: 45.0/4.0 },
-        Hit { x: 4, y: 3, direction: Direction::W, position: 0.25, distance: 245.0/16.0 }
+        Hit { x: 1, y: 1, direction: Direction::W, position: 0.75, squared_distance: 5.0/16.0 },
+        Hit { x: 1, y: 2, direction: Direction::N, position: 0.5, squared_distance: 5.0/4.0 },
+        Hit { x: 2, y: 2, direction: Direction::W, position: 0.25, squared_distance: 45.0/16.0 },
+        Hit { x: 3, y: 2, direction: Direction::W, position: 0.75, squared_distance: 125.0/16.0 },
+        Hit { x: 3, y: 3, direction: Direction::N, position: 0.5, squared_distance: 45.0/4.0 },
+        Hit { x: 4, y: 3, direction: Direction::W, position: 0.25, squared_distance: 245.0/16.0 }
     ];
 
     assert_eq!(hits, expected);
 
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_raycaster_exit() {
+    let grid = Grid { height: 5, width: 5 };
+
+    let outcomes: Vec<_> = Raycaster::new(v(2.5, 2.5), v(1.0, 0.0), grid).outcomes().collect();
+    let expected = vec![
+        RaycastOutcome::Hit(Hit { x: 3, y: 2, direction: Direction::W, position: 0.5, squared_distance: 0.25 }),
+        RaycastOutcome::Hit(Hit { x: 4, y: 2, direction: Direction::W, position: 0.5, squared_distance: 2.25 }),
+        RaycastOutcome::Exit { x: 4, y: 2, direction: Direction::E },
+    ];
+
+    assert_eq!(outcomes, expected);
+
+    let last = Raycaster::new(v(0.5, 1.5), v(2.0, 1.0), grid).outcomes().last();
+    assert_eq!(last, Some(RaycastOutcome::Exit { x: 4, y: 3, direction: Direction::E }));
+}
+
+#[test]
+fn test_raycaster_reset() {
+    let grid = Grid { height: 5, width: 5 };
+
+    let mut caster = Raycaster::new(v(2.5, 2.5), v(0.0, -1.0), grid);
+    assert!(caster.next().is_some());
+
+    caster.reset(v(0.5, 1.5), v(2.0, 1.0), grid);
+    let reset: Vec<_> = caster.by_ref().collect();
+    let fresh: Vec<_> = Raycaster::new(v(0.5, 1.5), v(2.0, 1.0), grid).collect();
+    assert_eq!(reset, fresh);
+    assert_eq!(caster.exit(), Some((4, 3, Direction::E)));
+}
+
+#[test]
+fn test_raycaster_leaves_above() {
+    // Nearly vertical rays cross the next grid line far outside the grid.
+    let grid = Grid { height: 5, width: 5 };
+    assert_eq!(Raycaster::new(v(2.5, 0.5), v(1e-16, -1.0), grid).next(), None);
+    assert_eq!(Raycaster::new(v(0.5, 2.5), v(-1.0, 1e-16), grid).next(), None);
+}
+
+#[test]
+fn test_cells_along() {
+    let grid = Grid { height: 5, width: 5 };
+
+    let cells: Vec<_> = grid.cells_along(v(0.2, 0.5), v(1.0, 1.0)).collect();
+    assert_eq!(cells, vec![(0, 0), (0, 1), (1, 1), (1, 2), (2, 2), (2, 3), (3, 3), (3, 4), (4, 4)]);
+
+    let corners: Vec<_> = grid.cells_along(v(0.5, 0.5), v(1.0, 1.0)).collect();
+    assert_eq!(corners, vec![(0, 0), (1, 1), (2, 2), (3, 3), (4, 4)]);
+}
+
+#[test]
+fn test_hit_distance() {
+    let hit = Hit { x: 0, y: 0, direction: Direction::N, position: 0.0, squared_distance: 6.25 };
+    assert_eq!(hit.distance(), 2.5);
+    assert_eq!(hit.distance(), hit.squared_distance.sqrt());
+}
+
+#[test]
+fn test_raycaster_wrapping() {
+    let grid = Grid { height: 1, width: 4 };
+
+    let hits: Vec<_> = Raycaster::new(v(2.5, 0.5), v(1.0, 0.0), grid)
+        .wrapping()
+        .take(4)
+        .map(|hit| (hit.x, hit.direction, hit.squared_distance))
+        .collect();
+    assert_eq!(hits, vec![
+        (3, Direction::W, 0.25),
+        (0, Direction::W, 2.25),
+        (1, Direction::W, 6.25),
+        (2, Direction::W, 12.25),
+    ]);
+
+    // Westwards the ray re-enters through the east face of the last column.
+    let hit = Raycaster::new(v(0.5, 0.5), v(-1.0, 0.0), grid).wrapping().next().unwrap();
+    assert_eq!((hit.x, hit.direction, hit.squared_distance), (3, Direction::E, 0.25));
+
+    // Open corridors end after MAX_WRAPS crossings.
+    assert_eq!(Raycaster::new(v(0.5, 0.5), v(1.0, 0.0), grid).wrapping().count(), 3 + MAX_WRAPS * 4);
+}
+
+#[test]
+fn test_raycaster_from_grid_line() {
+    let grid = Grid { height: 3, width: 5 };
+
+    // Eastwards, the ray starts in cell 2 and first crosses x = 3.
+    let mut east = Raycaster::new(v(2.0, 1.5), v(1.0, 0.0), grid);
+    assert_eq!(east.cell, Some((2, 1)));
+    let hit = east.next().unwrap();
+    assert_eq!((hit.x, hit.y, hit.direction, hit.squared_distance), (3, 1, Direction::W, 1.0));
+
+    // Westwards, it starts in cell 1 and first crosses x = 1.
+    let mut west = Raycaster::new(v(2.0, 1.5), v(-1.0, 0.0), grid);
+    assert_eq!(west.cell, Some((1, 1)));
+    let hit = west.next().unwrap();
+    assert_eq!((hit.x, hit.y, hit.direction, hit.squared_distance), (0, 1, Direction::E, 1.0));
+}
+
+#[test]
+fn test_raycaster_cutoff() {
+    let grid = Grid { height: 1, width: 100 };
+
+    let hits: Vec<_> = Raycaster::new(v(0.5, 0.5), v(1.0, 0.0), grid)
+        .with_cutoff(9.0)
+        .map(|hit| hit.x)
+        .collect();
+    assert_eq!(hits, vec![1, 2, 3]);
+
+    let mut caster = Raycaster::new(v(0.5, 0.5), v(1.0, 0.0), grid).with_cutoff(1.0);
+    caster.reset(v(10.5, 0.5), v(-1.0, 0.0), grid);
+    assert_eq!(caster.count(), 1);
+}
+
+#[test]
+fn test_lerp() {
+    let (a, b) = (v(0.0, 0.0), v(4.0, 2.0));
+    assert_eq!(a.lerp(b, 0.5), v(2.0, 1.0));
+    assert_eq!(a.lerp(b, 0.0), a);
+    assert_eq!(a.lerp(b, 1.0), b);
+}
+
+#[test]
+fn test_raycaster_degenerate() {
+    let g = Grid { width: 4, height: 4 };
+    for &d in [v(0.0, 0.0), v(1e-200, 0.0), v(f64::NAN, 1.0), v(f64::INFINITY, 0.0)].iter() {
+        assert!(Raycaster::new(v(1.5, 1.5), d, g).next().is_none(), "{:?}", d);
+        assert!(Raycaster::new(v(1.5, 1.5), d, g).wrapping().next().is_none(), "{:?}", d);
+    }
+
+    let mut caster = Raycaster::new(v(1.5, 1.5), v(1.0, 0.0), g);
+    caster.reset(v(1.5, 1.5), v(0.0, 0.0), g);
+    assert!(caster.next().is_none());
+
+    // Rays along an axis cross the lines of the other axis only.
+    let hits: Vec<_> = Raycaster::new(v(1.5, 1.5), v(0.0, 1.0), g).map(|h| (h.x, h.y, h.direction)).collect();
+    assert_eq!(hits, vec![(1, 2, Direction::N), (1, 3, Direction::N)]);
+    let hits: Vec<_> = Raycaster::new(v(2.5, 0.5), v(-2.0, 0.0), g).map(|h| (h.x, h.y, h.direction)).collect();
+    assert_eq!(hits, vec![(1, 0, Direction::E), (0, 0, Direction::E)]);
+}
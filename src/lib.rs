@@ -1,4 +1,5 @@
 pub mod loader;
 pub mod render;
 pub mod geometry;
-pub mod texture;
\ No newline at end of file
+pub mod texture;
+pub mod lint;
@@ -0,0 +1,227 @@
+use crate::{
+    loader::{self, grid_of, LoadOptions, Map, MapCell},
+    texture::Texture,
+};
+
+use {
+    anyhow::Result,
+    image::Rgb,
+    std::{collections::HashMap, fmt, io::BufRead},
+};
+
+#[derive(Debug,Clone,Copy,PartialEq,Eq,PartialOrd,Ord)]
+pub enum Severity {
+    /// The map loads, but probably not as its author meant.
+    Warning,
+    /// The map does not load.
+    Error,
+}
+
+/// A problem found in a map file by `lint`.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Issue {
+    pub severity: Severity,
+    /// The 1-based line of the file the issue is on, if any.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match self.line {
+            Some(line) => write!(f, "{}: line {}: {}", severity, line, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+/// Headers every map must declare.
+const REQUIRED: [&str; 6] = ["R", "NO", "SO", "WE", "EA", "S"];
+
+/// Checks a map file, reporting every problem found rather than stopping
+/// at the first one as `Map::load` does: malformed, missing, duplicate or
+/// unknown headers, textures that cannot be decoded, gaps in the border
+/// walls, unreachable items and grid errors. Only reading `source` can
+/// fail.
+pub fn lint<R: BufRead>(source: R, options: &LoadOptions) -> Result<Vec<Issue>> {
+    let lines: Vec<String> = loader::clean_lines(source).collect::<Result<_, _>>()?;
+    let mut issues = Vec::new();
+    let mut report = |severity, line: Option<usize>, message: String| {
+        issues.push(Issue { severity, line: line.map(|i| i + 1), message })
+    };
+
+    // Headers run up to the first line that isn't one, blank lines aside,
+    // as in `Map::load`.
+    let mut headers: HashMap<&str, (usize, &str)> = HashMap::new();
+    let mut grid_start = lines.len();
+    for (i, line) in lines.iter().enumerate() {
+        match line.chars().next() {
+            None => continue,
+            Some(_) if loader::is_header(line) => (),
+            _ => {
+                grid_start = i;
+                break;
+            }
+        }
+        let (key, value) = match line.find(' ') {
+            Some(at) => (&line[..at], line[at..].trim()),
+            None => {
+                report(Severity::Error, Some(i), format!("header without a value: {}", line));
+                continue;
+            }
+        };
+        if let Some((first, _)) = headers.insert(key, (i, value)) {
+            report(Severity::Warning, Some(i), format!("duplicate {} header, overriding line {}", key, first + 1));
+        }
+        let texture = loader::texture_headers().any(|k| k == key);
+        if !texture && !loader::HEADER_KEYS.contains(&key) {
+            report(Severity::Warning, Some(i), format!("unknown header {}", key));
+        }
+    }
+
+    for key in REQUIRED.iter() {
+        if !headers.contains_key(key) {
+            report(Severity::Error, None, format!("{} header missing", key));
+        }
+    }
+    for &(key, name) in [("F", "floor"), ("C", "ceiling")].iter() {
+        match headers.get(key) {
+            Some(&(i, value)) => if let Err(e) = loader::read_rgb(value) {
+                report(Severity::Error, Some(i), format!("{} header: {:#}", key, e));
+            },
+            None if options.strict => report(Severity::Error, None, format!("no {} color", name)),
+            None => report(Severity::Warning, None, format!("no {} color, using the default", name)),
+        }
+    }
+
+    for key in loader::texture_headers() {
+        let (i, spec) = match headers.get(key.as_str()) {
+            Some(&header) => header,
+            None => continue,
+        };
+        let (path, _) = loader::texture_spec(spec);
+        let texture = if path.starts_with("data:") {
+            loader::data_url(path).and_then(|data| Texture::from_bytes(&data))
+        } else {
+            Texture::load(path)
+        };
+        match texture {
+            Ok(texture) if texture.width() == 0 || texture.height() == 0 =>
+                report(Severity::Error, Some(i), format!("{} texture {} is empty", key, path)),
+            Ok(_) => (),
+            Err(e) => report(Severity::Error, Some(i), format!("{} texture {}: {:#}", key, path, e)),
+        }
+    }
+
+    // The line each level's grid starts on.
+    let mut starts = vec![grid_start];
+    starts.extend((grid_start..lines.len()).filter(|&i| lines[i] == loader::LEVEL_SEPARATOR).map(|i| i + 1));
+
+    let grid = lines[grid_start..].iter().cloned().map(Ok).peekable();
+    let (levels, spawn) = match loader::load_map(grid, options) {
+        Ok(grid) => grid,
+        Err(e) => {
+            report(Severity::Error, None, format!("{:#}", e));
+            return Ok(issues);
+        }
+    };
+
+    if !options.wrap {
+        for (level, data) in levels.iter().enumerate() {
+            let g = grid_of(data);
+            let edge = |x: usize, y: usize| x == 0 || y == 0 || x == g.width - 1 || y == g.height - 1;
+            for y in 0..g.height {
+                for x in (0..g.width).filter(|&x| edge(x, y) && data[[y, x]].is_walkable()) {
+                    report(Severity::Error, Some(starts[level] + y),
+                           format!("gap in the border wall of level {} at ({}, {})", level, x, y));
+                }
+            }
+        }
+    }
+
+    let data = &levels[spawn.level];
+    let seen = loader::reachable_cells(data, (spawn.x, spawn.y), options.wrap);
+    let g = grid_of(data);
+    let line = |y| Some(starts[spawn.level] + y);
+    if (0..g.height).all(|y| (0..g.width).all(|x| !seen[[y, x]] || (x, y) == (spawn.x, spawn.y))) {
+        report(Severity::Warning, line(spawn.y), format!("spawn point ({}, {}) is walled in", spawn.x, spawn.y));
+    }
+    for y in 0..g.height {
+        for x in 0..g.width {
+            if matches!(data[[y, x]], MapCell::Item(_)) && !seen[[y, x]] {
+                report(Severity::Warning, line(y), format!("item at ({}, {}) cannot be reached", x, y));
+            }
+        }
+    }
+
+    let has_windows = levels.iter().any(|level| {
+        let g = grid_of(level);
+        (0..g.height).any(|y| (0..g.width).any(|x| level[[y, x]] == MapCell::Window))
+    });
+    if has_windows && !headers.contains_key("G") {
+        report(Severity::Error, None, "window cells without a G texture".to_string());
+    }
+
+    // Let the loader find anything else, such as malformed header values,
+    // once the problems above are out of the way.
+    if issues.iter().all(|issue| issue.severity < Severity::Error) {
+        let lenient = LoadOptions { wrap: true, ..options.clone() };
+        let placeholder = |_: &str| Ok(Texture::from_noise(0, Rgb([0, 0, 0]), 0, 1));
+        if let Err(e) = Map::parse(lines.join("\n").as_bytes(), &lenient, placeholder, &mut |_, _| ()) {
+            issues.push(Issue { severity: Severity::Error, line: None, message: format!("{:#}", e) });
+        }
+    }
+
+    Ok(issues)
+}
+
+#[test]
+fn test_lint_sample() {
+    let issues = lint(&std::fs::read("sample.cub").unwrap()[..], &LoadOptions::default()).unwrap();
+    assert!(issues.iter().all(|issue| issue.severity == Severity::Warning), "{:?}", issues);
+}
+
+#[test]
+fn test_lint_broken() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/missing.png
+WE tex/west.png
+S tex/sprite.png
+F 220,100,0
+F 220,100,1
+XYZ 1
+
+111111
+1N0001
+100001
+111100
+121111
+";
+    let issues = lint(&data[..], &LoadOptions::default()).unwrap();
+    let report: Vec<_> = issues.iter().map(ToString::to_string).collect();
+
+    let expected = [
+        "error: EA header missing",
+        "error: line 4: SO texture tex/missing.png",
+        "warning: line 8: duplicate F header, overriding line 7",
+        "warning: line 9: unknown header XYZ",
+        "warning: no ceiling color",
+        "error: line 14: gap in the border wall of level 0 at (5, 3)",
+        "warning: line 15: item at (1, 4) cannot be reached",
+        "error: line 15: gap in the border wall of level 0 at (1, 4)",
+    ];
+    for expected in expected.iter() {
+        assert!(report.iter().any(|line| line.starts_with(expected)), "{} not in {:#?}", expected, report);
+    }
+    assert!(issues.iter().any(|issue| issue.severity == Severity::Error));
+
+    // A grid error stops the grid checks, not the header ones.
+    let issues = lint(&b"R 640 480\n\n11111\n1N?N1\n11111\n"[..], &LoadOptions::default()).unwrap();
+    assert!(issues.iter().filter(|issue| issue.severity == Severity::Error).count() >= 6, "{:#?}", issues);
+}
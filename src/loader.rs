@@ -1,4 +1,4 @@
-use crate::texture::Texture;
+use crate::{geometry::{Grid, Raycaster, Vector}, texture::{Texture, Transform, WrapMode}};
 
 use {
     anyhow::{
@@ -7,32 +7,133 @@ use {
         Result,
         Context,
     },
-    multiarray::{MultiArray, Array2D},
+    multiarray::Array2D,
     std::{
-        collections::HashMap,
+        collections::{HashMap, HashSet, VecDeque},
         convert::TryInto,
         fmt::Debug,
         io::{
+            self,
             BufRead,
-            Lines,
+            Write,
         },
         iter::Peekable,
     },
     image::Rgb,
 };
-#[derive(Clone,Copy,PartialEq,PartialOrd,Eq,Ord)]
+
+#[cfg(feature = "zip")]
+use std::{fs::File, io::Read, path::Path};
+
+#[cfg(feature = "prefetch")]
+use std::thread::JoinHandle;
+
+#[derive(Clone,Copy,PartialEq,PartialOrd,Eq,Ord,Debug)]
 pub enum MapCell {
     Space,
     Wall,
-    Item,
+    /// An item, tagged with the map digit (`2` to `9`) it was declared with.
+    Item(u8),
+    /// Blocks sight like a wall, but can be walked through.
+    Fog,
+    /// A wall tagged with the letter (`a` to `z`) it was declared with,
+    /// which selects per-cell attributes such as its tint.
+    TaggedWall(u8),
+    /// A see-through wall, such as a fence or a window, textured by the `G`
+    /// header. Only `Render::render_transparent` draws it.
+    Window,
+    /// A sliding door, open by the given fraction of 255 of its width. It is
+    /// textured by the `D` header, or like walls without one.
+    Door(u8),
+}
+
+/// The plane item sprites are drawn in.
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Billboard {
+    /// Always facing the camera.
+    ViewFacing,
+    /// Fixed along the world X axis, like a sign on a north or south wall.
+    AxisX,
+    /// Fixed along the world Y axis.
+    AxisY,
+}
+
+/// How a cell interacts with rays, sight and movement.
+#[derive(Clone,Copy,PartialEq,Eq,Debug,Default)]
+pub struct CellFlags(u8);
+
+impl CellFlags {
+    pub const NONE: CellFlags = CellFlags(0);
+    /// Rendered rays stop at the cell.
+    pub const OPAQUE: CellFlags = CellFlags(1);
+    /// The player cannot move into the cell.
+    pub const BLOCKS_MOVEMENT: CellFlags = CellFlags(2);
+    /// Lines of sight through the cell are blocked.
+    pub const BLOCKS_SIGHT: CellFlags = CellFlags(4);
+
+    pub fn contains(self, other: CellFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for CellFlags {
+    type Output = CellFlags;
+
+    fn bitor(self, other: CellFlags) -> CellFlags {
+        CellFlags(self.0 | other.0)
+    }
+}
+
+impl MapCell {
+    /// The flags of this kind of cell. Closed and partially open doors are
+    /// solid, though rays may still pass through the open part.
+    pub fn flags(self) -> CellFlags {
+        let solid = CellFlags::OPAQUE | CellFlags::BLOCKS_MOVEMENT | CellFlags::BLOCKS_SIGHT;
+        match self {
+            MapCell::Wall | MapCell::TaggedWall(_) => solid,
+            MapCell::Door(open) if open < DOOR_OPEN => solid,
+            MapCell::Fog => CellFlags::OPAQUE | CellFlags::BLOCKS_SIGHT,
+            MapCell::Window => CellFlags::BLOCKS_MOVEMENT,
+            MapCell::Space | MapCell::Item(_) | MapCell::Door(_) => CellFlags::NONE,
+        }
+    }
+
+    /// Whether rays stop at this cell. Rays may still pass through the open
+    /// part of doors.
+    pub fn is_opaque(self) -> bool {
+        self.flags().contains(CellFlags::OPAQUE)
+    }
+
+    /// Whether the player can move into this cell.
+    pub fn is_walkable(self) -> bool {
+        !self.flags().contains(CellFlags::BLOCKS_MOVEMENT)
+    }
+
+    /// Whether lines of sight through this cell are blocked.
+    pub fn blocks_sight(self) -> bool {
+        self.flags().contains(CellFlags::BLOCKS_SIGHT)
+    }
 }
 
+/// The opening of a fully open `MapCell::Door`.
+pub const DOOR_OPEN: u8 = 255;
+
 #[derive(Debug,Clone,Copy,PartialEq,Eq)]
 pub enum Direction {
     N,S,E,W
 }
 
 impl Direction {
+    pub fn opposite(self) -> Self {
+        use Direction::*;
+        match self {
+            N => S,
+            S => N,
+            E => W,
+            W => E,
+        }
+    }
+
     pub fn pointer(self) -> char {
         use Direction::*;
         match self {
@@ -43,6 +144,18 @@ impl Direction {
         }
     }
 
+    /// The unit vector pointing this way, the outward normal of the cell
+    /// face of this direction.
+    pub fn normal(self) -> Vector {
+        use Direction::*;
+        match self {
+            N => (0.0, -1.0),
+            S => (0.0, 1.0),
+            W => (-1.0, 0.0),
+            E => (1.0, 0.0),
+        }.into()
+    }
+
     pub fn angle(self) -> f64 {
         use Direction::*;
         match self {
@@ -59,21 +172,133 @@ pub struct Spawn {
     pub direction: Direction,
     pub x: usize,
     pub y: usize,
+    pub level: usize,
+}
+
+impl Spawn {
+    /// A spawn point at cell `(x, y)` of the spawn level of `map`, which
+    /// must be inside the map and walkable.
+    pub fn new_checked(map: &Map, x: usize, y: usize, direction: Direction) -> Result<Spawn> {
+        let grid = grid_of(map.spawn_level());
+        match map.get_cell(x, y) {
+            None => bail!("spawn point {:?} outside the {}x{} map", (x, y), grid.width, grid.height),
+            Some(cell) if !cell.is_walkable() => bail!("spawn point {:?} is in a {:?} cell", (x, y), cell),
+            Some(_) => Ok(Spawn { direction, x, y, level: map.spawn.level }),
+        }
+    }
 }
 
 pub type RGB = Rgb<u8>;
 
+/// A cell that differs between two maps, as found by `Map::diff`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct CellChange {
+    pub level: usize,
+    pub x: usize,
+    pub y: usize,
+    pub from: MapCell,
+    pub to: MapCell,
+}
+
+/// The differences between two maps of the same size.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct MapDiff {
+    /// Every differing cell, level by level in row order.
+    pub cells: Vec<CellChange>,
+    /// Whether the spawn point or direction differ.
+    pub spawn: bool,
+    /// Whether any header differs: resolution, field of view, colors,
+    /// tints, glow tags or texture sources.
+    pub headers: bool,
+}
+
 pub struct Map {
     pub resolution: (usize, usize),
+    /// The field of view suggested by the `FOV` header, in degrees from the
+    /// view axis to the edge of the screen.
+    pub fov: Option<f64>,
     pub textures: [Texture; 4],
+    /// The default item sprite, declared by the `S` header.
     pub sprite: Texture,
+    /// Sprites for specific item ids, declared by `S2` to `S9` headers.
+    pub sprites: HashMap<u8, Texture>,
+    /// Billboarding of specific item ids, declared by the `BB` header.
+    /// Items not listed face the camera.
+    pub billboards: HashMap<u8, Billboard>,
+    /// The texture of door cells, declared by the `D` header.
+    pub door: Option<Texture>,
+    /// The texture of window cells, declared by the `G` header.
+    pub window: Option<Texture>,
+    /// Colors multiplied into the texture of tagged walls, by tag.
+    pub tints: HashMap<u8, RGB>,
+    /// Tags of walls drawn at full brightness, ignoring fog and depth
+    /// cueing, declared by the `GLOW` header.
+    pub emissive: HashSet<u8>,
+    /// Heights of walls shorter than a full cell, as fractions of a wall
+    /// height, by tag. Declared by the `WH` header.
+    pub wall_heights: HashMap<u8, f64>,
     pub floor: RGB,
     pub ceiling: RGB,
+    /// Colors blended from the top of the screen down to the horizon in
+    /// place of `ceiling`, declared by the `CG` header.
+    pub ceiling_gradient: Option<(RGB, RGB)>,
+    /// Colors blended from the bottom of the screen, nearest, up to the
+    /// horizon in place of `floor`, declared by the `FG` header.
+    pub floor_gradient: Option<(RGB, RGB)>,
+    /// The ground level.
     pub data: Array2D<MapCell>,
+    /// Further levels stacked above the ground level, in file order.
+    pub levels: Vec<Array2D<MapCell>>,
     pub spawn: Spawn,
+    /// Whether the map is toroidal, each edge leading to the opposite one.
+    pub wrap: bool,
+}
+
+/// A read-only view of the cells of one map level.
+#[derive(Clone,Copy)]
+pub struct GridView<'a> {
+    data: &'a Array2D<MapCell>,
+}
+
+impl<'a> GridView<'a> {
+    /// Cell `(x, y)`, or `None` outside the level.
+    pub fn get(&self, x: usize, y: usize) -> Option<MapCell> {
+        if x < self.width() && y < self.height() { Some(self.data[[y, x]]) } else { None }
+    }
+
+    pub fn width(&self) -> usize {
+        self.data.extents()[1]
+    }
+
+    pub fn height(&self) -> usize {
+        self.data.extents()[0]
+    }
+
+    /// The rows of the level from top to bottom, each from west to east.
+    pub fn rows(&self) -> impl Iterator<Item = impl Iterator<Item = MapCell> + 'a> + 'a {
+        let (data, width) = (self.data, self.width());
+        (0..self.height()).map(move |y| (0..width).map(move |x| data[[y, x]]))
+    }
+}
+
+pub(crate) fn grid_of(data: &Array2D<MapCell>) -> Grid {
+    let extents = data.extents();
+    Grid { height: extents[0], width: extents[1] }
 }
 
 impl Map {
+    pub fn grid(&self) -> Grid {
+        grid_of(&self.data)
+    }
+
+    /// The grid of level `n`, where level 0 is the ground level `data`.
+    pub fn level(&self, n: usize) -> Option<&Array2D<MapCell>> {
+        match n {
+            0 => Some(&self.data),
+            n => self.levels.get(n - 1),
+        }
+    }
+
     pub fn texture(&self, d: Direction) -> &Texture {
         match d {
             Direction::N => &self.textures[0],
@@ -82,37 +307,503 @@ impl Map {
             Direction::E => &self.textures[3],
         }
     }
+
+    /// The wall texture of every face direction, in `NO`, `SO`, `WE`, `EA`
+    /// header order.
+    pub fn wall_textures(&self) -> impl Iterator<Item = (Direction, &Texture)> {
+        [Direction::N, Direction::S, Direction::W, Direction::E].iter()
+            .map(move |&d| (d, self.texture(d)))
+    }
+
+    /// The sprite for items tagged `id`, falling back to the default sprite.
+    pub fn sprite_for(&self, id: u8) -> &Texture {
+        self.sprites.get(&id).unwrap_or(&self.sprite)
+    }
+
+    /// The height of `cell` as a fraction of a full wall: tagged walls may
+    /// be shorter, every other cell is full height.
+    pub fn wall_height(&self, cell: MapCell) -> f64 {
+        match cell {
+            MapCell::TaggedWall(tag) => self.wall_heights.get(&tag).copied().unwrap_or(1.0),
+            _ => 1.0,
+        }
+    }
+
+    /// How items tagged `id` are billboarded.
+    pub fn billboard_for(&self, id: u8) -> Billboard {
+        self.billboards.get(&id).copied().unwrap_or(Billboard::ViewFacing)
+    }
+
+    /// The grid of the level the player spawns on.
+    pub fn spawn_level(&self) -> &Array2D<MapCell> {
+        self.level(self.spawn.level).expect("spawn on a missing level")
+    }
+
+    /// A read-only view of the spawn level.
+    pub fn grid_view(&self) -> GridView<'_> {
+        GridView { data: self.spawn_level() }
+    }
+
+    /// Cell `(x, y)` of the spawn level, or `None` outside the map.
+    pub fn get_cell(&self, x: usize, y: usize) -> Option<MapCell> {
+        let data = self.spawn_level();
+        let (h,w) = (data.extents()[0], data.extents()[1]);
+        if x < w && y < h { Some(data[[y, x]]) } else { None }
+    }
+
+    /// Replaces cell `(x, y)` of the spawn level, returning `false` and
+    /// leaving the map untouched if it is outside the map.
+    pub fn set_cell(&mut self, x: usize, y: usize, cell: MapCell) -> bool {
+        let data = match self.spawn.level {
+            0 => &mut self.data,
+            n => &mut self.levels[n - 1],
+        };
+        let (h,w) = (data.extents()[0], data.extents()[1]);
+        if x < w && y < h {
+            data[[y, x]] = cell;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Crops or extends every level to `width`x`height` cells, keeping the
+    /// top-left corner in place and filling new cells with walls. The map is
+    /// left untouched if the spawn point or a walkable cell would end up out
+    /// of bounds or, unless the map wraps, on the border.
+    pub fn resize(&mut self, width: usize, height: usize) -> Result<()> {
+        if self.spawn.x >= width || self.spawn.y >= height {
+            bail!("spawn point {:?} outside a {}x{} map", (self.spawn.x, self.spawn.y), width, height);
+        }
+        if width == 0 || height == 0 {
+            bail!("cannot resize map to {}x{}", width, height);
+        }
+
+        let resized = std::iter::once(&self.data).chain(&self.levels)
+            .map(|level| {
+                let (h,w) = (level.extents()[0], level.extents()[1]);
+                let cropped = (0..h).flat_map(|y| (0..w).map(move |x| (x, y)))
+                    .find(|&(x, y)| (x >= width || y >= height) && level[[y, x]].is_walkable());
+                if let Some((x, y)) = cropped {
+                    bail!("walkable cell {:?} outside a {}x{} map", (x, y), width, height);
+                }
+                let mut data = Array2D::new([height, width], MapCell::Wall);
+                for y in 0..h.min(height) {
+                    for x in 0..w.min(width) {
+                        data[[y, x]] = level[[y, x]];
+                    }
+                }
+                if !self.wrap {
+                    check_borders(&data)?;
+                }
+                Ok(data)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut resized = resized.into_iter();
+        self.data = resized.next().unwrap();
+        self.levels = resized.collect();
+        Ok(())
+    }
+
+    /// Whether the player can stand in cell `(x, y)` of the spawn level.
+    /// Cells outside the map are not walkable, unless the map wraps, in which
+    /// case coordinates past the east and south edges wrap around.
+    pub fn is_walkable(&self, x: usize, y: usize) -> bool {
+        let (x, y) = if self.wrap {
+            let grid = grid_of(self.spawn_level());
+            (x % grid.width, y % grid.height)
+        } else {
+            (x, y)
+        };
+        self.get_cell(x, y).is_some_and(MapCell::is_walkable)
+    }
+
+    /// Flood-fills the spawn level from the spawn point through 4-connected
+    /// walkable cells, marking every cell the player can walk to.
+    pub fn reachable(&self) -> Array2D<bool> {
+        reachable_cells(self.spawn_level(), (self.spawn.x, self.spawn.y), self.wrap)
+    }
+
+    /// The number of steps between 4-connected walkable cells of the spawn
+    /// level on a shortest path from `from` to `to`, or `None` if either
+    /// cell is not walkable or `to` cannot be reached.
+    pub fn grid_distance(&self, from: (usize, usize), to: (usize, usize)) -> Option<usize> {
+        let data = self.spawn_level();
+        let (h,w) = (data.extents()[0], data.extents()[1]);
+        let walkable = |(x, y): (usize, usize)| x < w && y < h && data[[y, x]].is_walkable();
+        if !walkable(from) || !walkable(to) {
+            return None;
+        }
+
+        let mut steps = Array2D::new([h, w], None);
+        let mut queue = VecDeque::new();
+        steps[[from.1, from.0]] = Some(0);
+        queue.push_back(from);
+
+        while let Some((x, y)) = queue.pop_front() {
+            let n = steps[[y, x]]?;
+            if (x, y) == to {
+                return Some(n);
+            }
+            for &(nx, ny) in &neighbours((x, y), (w, h), self.wrap) {
+                if walkable((nx, ny)) && steps[[ny, nx]].is_none() {
+                    steps[[ny, nx]] = Some(n + 1);
+                    queue.push_back((nx, ny));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// The `(x, y)` coordinates of every item on the spawn level that cannot
+    /// be reached from the spawn point, which usually indicates a mistake in
+    /// the map.
+    pub fn unreachable_items(&self) -> Vec<(usize, usize)> {
+        let data = self.spawn_level();
+        let (h,w) = (data.extents()[0], data.extents()[1]);
+        let seen = self.reachable();
+
+        (0..h).flat_map(|y| (0..w).map(move |x| (x, y)))
+            .filter(|&(x, y)| matches!(data[[y, x]], MapCell::Item(_)) && !seen[[y, x]])
+            .collect()
+    }
+
+    /// The number of walkable cells of the spawn level, items and open
+    /// doors included, whether or not they can be reached.
+    pub fn walkable_area(&self) -> usize {
+        self.count_cells(MapCell::is_walkable)
+    }
+
+    /// The number of items per walkable cell of the spawn level, or 0 on a
+    /// level without walkable cells.
+    pub fn item_density(&self) -> f64 {
+        let items = self.count_cells(|cell| matches!(cell, MapCell::Item(_)));
+        match self.walkable_area() {
+            0 => 0.0,
+            area => items as f64 / area as f64,
+        }
+    }
+
+    fn count_cells(&self, f: impl Fn(MapCell) -> bool) -> usize {
+        let data = self.spawn_level();
+        let (h,w) = (data.extents()[0], data.extents()[1]);
+        (0..h).flat_map(|y| (0..w).map(move |x| (x, y)))
+            .filter(|&(x, y)| f(data[[y, x]]))
+            .count()
+    }
+
+    /// Whether the segment from `from` to `to` crosses no cell of the spawn
+    /// level that blocks sight.
+    pub fn line_of_sight(&self, from: Vector, to: Vector) -> bool {
+        let data = self.spawn_level();
+        let length = from.squared_distance(&to);
+        Raycaster::new(from, to - from, grid_of(data))
+            .with_cutoff(length)
+            .all(|h| !data[[h.y, h.x]].blocks_sight())
+    }
+
+    /// The first item cell of the spawn level entered by the ray from `from`
+    /// along `dir`, or `None` if the ray reaches a cell blocking sight, or
+    /// leaves the map, first.
+    pub fn first_item_along(&self, from: Vector, dir: Vector) -> Option<(usize, usize)> {
+        let data = self.spawn_level();
+        Raycaster::new(from, dir, grid_of(data))
+            .map(|h| (h.x, h.y, data[[h.y, h.x]]))
+            .find(|&(_, _, cell)| matches!(cell, MapCell::Item(_)) || cell.blocks_sight())
+            .and_then(|(x, y, cell)| match cell {
+                MapCell::Item(_) => Some((x, y)),
+                _ => None,
+            })
+    }
+
+    /// The fraction of `samples` lines of sight from `from` that reach a one
+    /// cell wide segment centered on `to` and facing `from`. Points half
+    /// hidden behind a wall edge are partially visible.
+    pub fn visibility(&self, from: Vector, to: Vector, samples: usize) -> f64 {
+        let side = (to - from).turn();
+        let side = side * (1.0 / side.squared_norm().sqrt());
+
+        let visible = (0..samples)
+            .map(|i| (i as f64 + 0.5) / samples as f64 - 0.5)
+            .filter(|&offset| self.line_of_sight(from, to + side * offset))
+            .count();
+
+        visible as f64 / samples as f64
+    }
 }
 
-impl Debug for Map {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "resolution {}x{}\n", self.resolution.0, self.resolution.1)?;
-        write!(f, "floor: {:?}\n", self.floor)?;
-        write!(f, "ceiling: {:?}\n", self.ceiling)?;
-        write!(f, "spawn: {:?}\n", self.spawn)?;
+impl Map {
+    /// Draws every level as text: `.` for space, `#` for walls, `~` for fog,
+    /// `=` for windows, `D` for doors, the tag letter for tagged walls, `*`
+    /// for items and the spawn pointer, followed by a count of each kind of
+    /// cell. With `show_ids`, items are drawn as their id digit instead.
+    pub fn render_ascii(&self, show_ids: bool) -> String {
+        let mut out = String::new();
 
-        let (h,w) = (self.data.extents()[0], self.data.extents()[1]);
+        for (n, data) in std::iter::once(&self.data).chain(&self.levels).enumerate() {
+            let (h,w) = (data.extents()[0], data.extents()[1]);
+            let (mut walls, mut windows, mut doors, mut items, mut fog) = (0, 0, 0, 0, 0);
 
-        write!(f, "map layout: {}x{}\n", h, w)?;
+            if n == 0 {
+                out += &format!("map layout: {}x{}\n", h, w);
+            } else {
+                out += &format!("level {} layout: {}x{}\n", n, h, w);
+            }
 
-        for y in 0..h {
-            for x in 0..w {
-                let c = match self.data[[y,x]] {
-                    _ if self.spawn.x == x && self.spawn.y == y => self.spawn.direction.pointer(),
-                    MapCell::Space => '.',
-                    MapCell::Item => '*',
-                    MapCell::Wall => '#',
-                };
-                write!(f, "{}", c)?;
+            for y in 0..h {
+                for x in 0..w {
+                    let cell = data[[y,x]];
+                    match cell {
+                        MapCell::Wall | MapCell::TaggedWall(_) => walls += 1,
+                        MapCell::Window => windows += 1,
+                        MapCell::Door(_) => doors += 1,
+                        MapCell::Item(_) => items += 1,
+                        MapCell::Fog => fog += 1,
+                        MapCell::Space => (),
+                    }
+                    out.push(match cell {
+                        _ if self.spawn.level == n && self.spawn.x == x && self.spawn.y == y => self.spawn.direction.pointer(),
+                        MapCell::Space => '.',
+                        MapCell::Item(id) if show_ids => (b'0' + id) as char,
+                        MapCell::Item(_) => '*',
+                        MapCell::Fog => '~',
+                        MapCell::Wall => '#',
+                        MapCell::TaggedWall(id) => id as char,
+                        MapCell::Window => '=',
+                        MapCell::Door(_) => 'D',
+                    });
+                }
+                out.push('\n');
+            }
+
+            out += &format!("{} walls, {} windows, {} doors, {} items, {} fog\n", walls, windows, doors, items, fog);
+        }
+
+        out
+    }
+}
+
+impl Map {
+    /// The changes turning `self` into `other`, an editor's undo step.
+    /// Both maps must have the same levels, of the same sizes.
+    pub fn diff(&self, other: &Map) -> Result<MapDiff> {
+        let ours: Vec<_> = std::iter::once(&self.data).chain(&self.levels).collect();
+        let theirs: Vec<_> = std::iter::once(&other.data).chain(&other.levels).collect();
+        if ours.len() != theirs.len() {
+            bail!("cannot diff a map of {} levels with one of {}", ours.len(), theirs.len());
+        }
+
+        let mut cells = Vec::new();
+        for (level, (a, b)) in ours.into_iter().zip(theirs).enumerate() {
+            let (ga, gb) = (grid_of(a), grid_of(b));
+            if ga != gb {
+                bail!("level {} is {}x{} in one map and {}x{} in the other",
+                      level, ga.width, ga.height, gb.width, gb.height);
+            }
+            for y in 0..ga.height {
+                for x in 0..ga.width {
+                    if a[[y, x]] != b[[y, x]] {
+                        cells.push(CellChange { level, x, y, from: a[[y, x]], to: b[[y, x]] });
+                    }
+                }
+            }
+        }
+
+        let sources = |map: &Map| {
+            let mut sources: Vec<_> = map.textures.iter().chain(Some(&map.sprite))
+                .chain(map.door.as_ref()).chain(map.window.as_ref())
+                .map(|texture| texture.source().map(str::to_owned))
+                .collect();
+            let mut sprites: Vec<_> = map.sprites.iter()
+                .map(|(id, texture)| (*id, texture.source().map(str::to_owned)))
+                .collect();
+            sprites.sort();
+            sources.extend(sprites.into_iter().map(|(id, source)| source.map(|s| format!("S{} {}", id, s))));
+            sources
+        };
+        let headers = self.resolution != other.resolution
+            || self.fov != other.fov
+            || (self.floor, self.ceiling) != (other.floor, other.ceiling)
+            || (self.ceiling_gradient, self.floor_gradient) != (other.ceiling_gradient, other.floor_gradient)
+            || self.tints != other.tints
+            || self.emissive != other.emissive
+            || self.billboards != other.billboards
+            || self.wall_heights != other.wall_heights
+            || (self.door.is_some(), self.window.is_some()) != (other.door.is_some(), other.window.is_some())
+            || sources(self) != sources(other);
+
+        Ok(MapDiff { cells, spawn: self.spawn != other.spawn, headers })
+    }
+
+    /// Writes the map in `.cub` format, such that `Map::load` reads it back
+    /// to the same map. Textures are written as the header values they were
+    /// loaded from, and doors are written closed.
+    pub fn write_cub<W: Write>(&self, w: &mut W) -> Result<()> {
+        let source = |key: &str, texture: &Texture| {
+            texture.source().map(str::to_owned).ok_or(anyhow!("{} texture has no source to write", key))
+        };
+        let rgb = |Rgb([r, g, b]): RGB| format!("{},{},{}", r, g, b);
+
+        writeln!(w, "R {} {}", self.resolution.0, self.resolution.1)?;
+        for (key, texture) in ["NO", "SO", "WE", "EA"].iter().zip(&self.textures) {
+            writeln!(w, "{} {}", key, source(key, texture)?)?;
+        }
+        writeln!(w, "S {}", source("S", &self.sprite)?)?;
+        for id in 2..=9 {
+            if let Some(sprite) = self.sprites.get(&id) {
+                let key = format!("S{}", id);
+                writeln!(w, "{} {}", key, source(&key, sprite)?)?;
+            }
+        }
+        if let Some(door) = &self.door {
+            writeln!(w, "D {}", source("D", door)?)?;
+        }
+        if let Some(window) = &self.window {
+            writeln!(w, "G {}", source("G", window)?)?;
+        }
+        writeln!(w, "F {}", rgb(self.floor))?;
+        writeln!(w, "C {}", rgb(self.ceiling))?;
+        if let Some((top, horizon)) = self.ceiling_gradient {
+            writeln!(w, "CG {} {}", rgb(top), rgb(horizon))?;
+        }
+        if let Some((near, far)) = self.floor_gradient {
+            writeln!(w, "FG {} {}", rgb(near), rgb(far))?;
+        }
+        if !self.tints.is_empty() {
+            let mut tints: Vec<_> = self.tints.iter().collect();
+            tints.sort_by_key(|&(&id, _)| id);
+            let tints: Vec<_> = tints.into_iter()
+                .map(|(&id, &tint)| format!("{}:{}", id as char, rgb(tint)))
+                .collect();
+            writeln!(w, "TINT {}", tints.join(" "))?;
+        }
+        if !self.emissive.is_empty() {
+            let mut tags: Vec<_> = self.emissive.iter().map(|&id| id as char).collect();
+            tags.sort();
+            writeln!(w, "GLOW {}", tags.into_iter().collect::<String>())?;
+        }
+        if !self.wall_heights.is_empty() {
+            let mut heights: Vec<_> = self.wall_heights.iter().collect();
+            heights.sort_by_key(|&(&id, _)| id);
+            let heights: Vec<_> = heights.into_iter()
+                .map(|(&id, height)| format!("{}:{}", id as char, height))
+                .collect();
+            writeln!(w, "WH {}", heights.join(" "))?;
+        }
+        if !self.billboards.is_empty() {
+            let mut modes: Vec<_> = self.billboards.iter().collect();
+            modes.sort_by_key(|&(&id, _)| id);
+            let modes: Vec<_> = modes.into_iter()
+                .map(|(&id, &mode)| format!("{}:{}", id, match mode {
+                    Billboard::ViewFacing => 'V',
+                    Billboard::AxisX => 'X',
+                    Billboard::AxisY => 'Y',
+                }))
+                .collect();
+            writeln!(w, "BB {}", modes.join(" "))?;
+        }
+        if let Some(fov) = self.fov {
+            writeln!(w, "FOV {}", fov)?;
+        }
+
+        for (n, data) in std::iter::once(&self.data).chain(&self.levels).enumerate() {
+            writeln!(w, "{}", if n == 0 { "" } else { LEVEL_SEPARATOR })?;
+            let grid = grid_of(data);
+            for y in 0..grid.height {
+                let row = (0..grid.width)
+                    .map(|x| Ok(match data[[y, x]] {
+                        _ if self.spawn.level == n && self.spawn.x == x && self.spawn.y == y =>
+                            match self.spawn.direction {
+                                Direction::N => 'N',
+                                Direction::S => 'S',
+                                Direction::E => 'E',
+                                Direction::W => 'W',
+                            },
+                        MapCell::Space => '0',
+                        MapCell::Wall => '1',
+                        MapCell::Item(id @ 2..=9) => (b'0' + id) as char,
+                        MapCell::Item(id) => bail!("item id {} has no map digit", id),
+                        MapCell::Fog => 'F',
+                        MapCell::TaggedWall(id @ b'a'..=b'z') => id as char,
+                        MapCell::TaggedWall(id) => bail!("wall tag {} is not a lowercase letter", id),
+                        MapCell::Window => 'G',
+                        MapCell::Door(_) => 'D',
+                    }))
+                    .collect::<Result<String>>()?;
+                writeln!(w, "{}", row)?;
             }
-            write!(f, "\n")?;
         }
+
+        Ok(())
+    }
+}
+
+impl Debug for Map {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "resolution {}x{}", self.resolution.0, self.resolution.1)?;
+        writeln!(f, "floor: {:?}", self.floor)?;
+        writeln!(f, "ceiling: {:?}", self.ceiling)?;
+        writeln!(f, "spawn: {:?}", self.spawn)?;
+
+        write!(f, "{}", self.render_ascii(true))?;
         Ok(())
 
     }
 }
 
-fn headers<R: BufRead>(lines: &mut Peekable<Lines<R>>) -> Result<HashMap<String,String>> {
+/// The 4-connected neighbours of cell `(x, y)` of a `w`x`h` grid, wrapping
+/// around the edges if `wrap` is set. Without wrapping, neighbours past the
+/// edges are out of range.
+fn neighbours((x, y): (usize, usize), (w, h): (usize, usize), wrap: bool) -> [(usize, usize); 4] {
+    if wrap {
+        [((x + w - 1) % w, y), ((x + 1) % w, y), (x, (y + h - 1) % h), (x, (y + 1) % h)]
+    } else {
+        [(x.wrapping_sub(1), y), (x + 1, y), (x, y.wrapping_sub(1)), (x, y + 1)]
+    }
+}
+
+/// Flood-fills `data` from `(x, y)` through 4-connected walkable cells, the
+/// edges leading round to the opposite edges if `wrap` is set.
+pub(crate) fn reachable_cells(data: &Array2D<MapCell>, (x, y): (usize, usize), wrap: bool) -> Array2D<bool> {
+    let (h,w) = (data.extents()[0], data.extents()[1]);
+    let mut seen = Array2D::new([h, w], false);
+    let mut queue = VecDeque::new();
+
+    seen[[y, x]] = true;
+    queue.push_back((x, y));
+
+    while let Some((x,y)) = queue.pop_front() {
+        for &(nx, ny) in &neighbours((x, y), (w, h), wrap) {
+            if nx < w && ny < h && data[[ny, nx]].is_walkable() && !seen[[ny, nx]] {
+                seen[[ny, nx]] = true;
+                queue.push_back((nx, ny));
+            }
+        }
+    }
+
+    seen
+}
+
+/// Strips a UTF-8 byte order mark from the first line, and a trailing
+/// carriage return from every line, so that files authored on Windows
+/// parse like their LF counterparts.
+pub(crate) fn clean_lines<R: BufRead>(source: R) -> impl Iterator<Item = io::Result<String>> {
+    source.lines().enumerate().map(|(i, line)| line.map(|mut line| {
+        if i == 0 && line.starts_with('\u{FEFF}') {
+            line.remove(0);
+        }
+        if line.ends_with('\r') {
+            line.pop();
+        }
+        line
+    }))
+}
+
+fn headers<I>(lines: &mut Peekable<I>) -> Result<HashMap<String,String>>
+    where I: Iterator<Item = io::Result<String>>
+{
     let mut h = HashMap::new();
 
     loop{
@@ -125,7 +816,7 @@ fn headers<R: BufRead>(lines: &mut Peekable<Lines<R>>) -> Result<HashMap<String,
 
         match line.chars().next() {
             None => { lines.next(); continue },
-            Some(x) if x.is_alphabetic() => (),
+            Some(_) if is_header(line) => (),
             _ => break,
         }
 
@@ -140,15 +831,113 @@ fn headers<R: BufRead>(lines: &mut Peekable<Lines<R>>) -> Result<HashMap<String,
     Ok(h)
 }
 
-fn load_map<R: BufRead>(lines: Peekable<Lines<R>>) -> Result<(Array2D<MapCell>, Spawn)> {
-    let lines: Result<Vec<String>> = lines
-        .map(|i| i.map_err(|e| anyhow!("io error while reading map data {}",e)))
-        .collect();
+/// The headers `Map::load` reads, besides the texture ones.
+pub(crate) const HEADER_KEYS: [&str; 13] = ["R", "M", "D", "G", "F", "C", "CG", "FG", "TINT", "GLOW", "WH", "BB", "FOV"];
 
-    let lines = lines.context("processing file header")?;
+/// Whether `line` is a header rather than the first row of the grid, which
+/// may start with the letter of a tagged wall, window or door: the line
+/// starts with a letter, and its first word is a header key or holds a
+/// character no grid cell is written with.
+pub(crate) fn is_header(line: &str) -> bool {
+    let key = line.split(' ').next().unwrap_or("");
+    let grid = |c: char| matches!(c, '0'..='9' | 'a'..='z' | 'F' | 'G' | 'D' | 'N' | 'S' | 'E' | 'W');
+    line.starts_with(char::is_alphabetic)
+        && (HEADER_KEYS.contains(&key) || texture_headers().any(|k| k == key) || !key.chars().all(grid))
+}
+
+/// The headers declaring textures.
+pub(crate) fn texture_headers() -> impl Iterator<Item = String> {
+    ["NO", "SO", "WE", "EA", "S", "D", "G"].iter().map(|k| k.to_string())
+        .chain((2..=9).map(|id| format!("S{}", id)))
+}
+
+/// Splits a texture header into the texture path and the transforms named
+/// after it, as in `NO tex/brick.png flipx rot90`.
+pub(crate) fn texture_spec(spec: &str) -> (&str, Vec<Transform>) {
+    let mut path = spec.trim_end();
+    let mut transforms = Vec::new();
+
+    while let Some(i) = path.rfind(' ') {
+        match Transform::from_name(&path[i + 1..]) {
+            Some(t) => {
+                transforms.push(t);
+                path = path[..i].trim_end();
+            }
+            None => break,
+        }
+    }
+
+    transforms.reverse();
+    (path, transforms)
+}
+
+/// Decodes the payload of a `data:` URL texture path, as in
+/// `NO data:image/png;base64,iVBOR...`. Only base64 payloads are supported.
+pub(crate) fn data_url(path: &str) -> Result<Vec<u8>> {
+    let (meta, payload) = path.split_at(path.find(',').ok_or(anyhow!("data URL without ','"))?);
+    if !meta.ends_with(";base64") {
+        bail!("only base64 data URLs are supported");
+    }
+    decode_base64(&payload[1..])
+}
+
+fn decode_base64(s: &str) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut bits, mut n) = (0u32, 0);
+
+    for c in s.bytes().take_while(|&c| c != b'=') {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => bail!("invalid base64 character {:?}", c as char),
+        };
+        bits = bits << 6 | sextet as u32;
+        n += 6;
+        if n >= 8 {
+            n -= 8;
+            out.push((bits >> n) as u8);
+        }
+    }
+
+    Ok(out)
+}
+
+/// Options controlling how a map file is interpreted.
+#[derive(Debug,Clone,Default)]
+pub struct LoadOptions {
+    /// Swap the meanings of `0` and `1` in the map grid, for maps where `1`
+    /// is floor and `0` is wall. Blanks are still walls.
+    pub invert: bool,
+    /// Require the `F` and `C` color headers instead of falling back to
+    /// `DEFAULT_FLOOR` and `DEFAULT_CEILING`.
+    pub strict: bool,
+    /// Load a toroidal map, whose edges need not be walls. See `Map::wrap`.
+    pub wrap: bool,
+}
+
+/// Floor color of maps without an `F` header.
+pub const DEFAULT_FLOOR: RGB = Rgb([64, 64, 64]);
+/// Ceiling color of maps without a `C` header.
+pub const DEFAULT_CEILING: RGB = Rgb([192, 192, 192]);
+
+pub(crate) const LEVEL_SEPARATOR: &str = "LEVEL";
+
+/// Parses one grid block, returning the spawn point if the block has one.
+fn load_grid(lines: &[String], level: usize, options: &LoadOptions) -> Result<(Array2D<MapCell>, Option<Spawn>)> {
+    let (zero, one) = if options.invert {
+        (MapCell::Wall, MapCell::Space)
+    } else {
+        (MapCell::Space, MapCell::Wall)
+    };
 
     let height = lines.len();
-    let width = lines.iter().map(|s| s.len()).max().unwrap();
+    let width = lines.iter().map(|s| s.len()).max().unwrap_or(0);
+    if height == 0 || width == 0 {
+        bail!("empty map grid on level {}", level);
+    }
     let mut data = Array2D::new([height, width], MapCell::Wall);
 
     let mut spawn = None;
@@ -158,7 +947,7 @@ fn load_map<R: BufRead>(lines: Peekable<Lines<R>>) -> Result<(Array2D<MapCell>,
         for (x, cell) in row.chars().enumerate() {
 
             let mut set_spawn = |d| {
-                match spawn.replace(Spawn {x,y,direction: d}) {
+                match spawn.replace(Spawn {x,y,level,direction: d}) {
                     None => Ok(()),
                     Some(s) =>
                         Err(anyhow!("More than one spawn point found ({:?} and {:?})", (s.y,s.x), (y, x)))
@@ -166,9 +955,14 @@ fn load_map<R: BufRead>(lines: Peekable<Lines<R>>) -> Result<(Array2D<MapCell>,
             };
 
             data[[y,x]] = match cell {
-                '0'     => MapCell::Space,
-                '1'|' ' => MapCell::Wall,
-                '2' => MapCell::Item,
+                '0' => zero,
+                '1' => one,
+                ' ' => MapCell::Wall,
+                '2'..='9' => MapCell::Item(cell as u8 - b'0'),
+                'F' => MapCell::Fog,
+                'G' => MapCell::Window,
+                'D' => MapCell::Door(0),
+                'a'..='z' => MapCell::TaggedWall(cell as u8),
                 'N' => { set_spawn(Direction::N)?; MapCell::Space }
                 'S' => { set_spawn(Direction::S)?; MapCell::Space }
                 'E' => { set_spawn(Direction::E)?; MapCell::Space }
@@ -178,12 +972,42 @@ fn load_map<R: BufRead>(lines: Peekable<Lines<R>>) -> Result<(Array2D<MapCell>,
         }
     }
 
+    Ok((data, spawn))
+}
+
+/// Parses the grid blocks following the headers. Blocks are separated by
+/// `LEVEL` lines; the first block is the ground level, and exactly one
+/// block must contain the spawn point.
+pub(crate) fn load_map<I>(lines: Peekable<I>, options: &LoadOptions) -> Result<(Vec<Array2D<MapCell>>, Spawn)>
+    where I: Iterator<Item = io::Result<String>>
+{
+    let lines: Result<Vec<String>> = lines
+        .map(|i| i.map_err(|e| anyhow!("io error while reading map data {}",e)))
+        .collect();
+
+    let lines = lines.context("processing file header")?;
+
+    let mut levels = Vec::new();
+    let mut spawn: Option<Spawn> = None;
+
+    for (level, block) in lines.split(|line| line == LEVEL_SEPARATOR).enumerate() {
+        let (data, found) = load_grid(block, level, options)?;
+
+        if let Some(found) = found {
+            if let Some(s) = spawn.replace(found) {
+                bail!("More than one spawn point found (on levels {} and {})", s.level, level);
+            }
+        }
+
+        levels.push(data);
+    }
+
     let spawn = spawn.ok_or(anyhow!("map without spawn point"))?;
 
-    Ok((data, spawn))
+    Ok((levels, spawn))
 }
 
-fn read_rgb(s: &str) -> Result<RGB> {
+pub(crate) fn read_rgb(s: &str) -> Result<RGB> {
     let pixel= s.split(',')
         .map(|s| Ok(s.parse()?))
         .collect::<Result<Vec<u8>>>()?
@@ -194,25 +1018,99 @@ fn read_rgb(s: &str) -> Result<RGB> {
     Ok(Rgb(pixel))
 }
 
-fn check_borders(data: &Array2D<MapCell>) -> Result<()> {
+/// Reads a gradient header: two space-separated `<r>,<g>,<b>` colors.
+fn read_gradient(s: &str) -> Result<(RGB, RGB)> {
+    let colors: Vec<_> = s.split_whitespace().collect();
+    if colors.len() != 2 {
+        bail!("two colors expected");
+    }
+    Ok((read_rgb(colors[0])?, read_rgb(colors[1])?))
+}
 
-    let (h,w) = (data.extents()[0], data.extents()[1]);
+/// Reads a `GLOW` header: the lowercase letters of emissive wall tags,
+/// optionally separated by spaces.
+fn read_tags(s: &str) -> Result<HashSet<u8>> {
+    s.chars()
+        .filter(|c| !c.is_whitespace())
+        .map(|c| match c {
+            'a'..='z' => Ok(c as u8),
+            _ => bail!("glow tag must be a lowercase letter: {}", c),
+        })
+        .collect()
+}
 
-    for y in &[0, h-1] {
-        for x in 0..w {
-            if data[[*y,x]] != MapCell::Wall {
-                bail!("Edge cell isn't a wall at ({},{})", y, x);
+/// Reads a `TINT` header: space-separated `<letter>:<r>,<g>,<b>` entries.
+fn read_palette(s: &str) -> Result<HashMap<u8, RGB>> {
+    s.split_whitespace()
+        .map(|entry| {
+            let (id, rgb) = entry.split_at(entry.find(':').ok_or(anyhow!("tint entry without ':': {}", entry))?);
+            match id.as_bytes() {
+                &[id @ b'a'..=b'z'] => Ok((id, read_rgb(&rgb[1..])?)),
+                _ => bail!("tint id must be a single lowercase letter: {}", id),
             }
-        }
-    }
+        })
+        .collect()
+}
 
-    for x in &[0, w-1] {
-        for y in 0..h {
-            if data[[y,*x]] != MapCell::Wall {
-                bail!("Edge cell isn't a wall at ({},{})", y, x);
+/// Reads a `WH` header: space-separated `<letter>:<height>` entries, with
+/// heights in `(0, 1]`.
+fn read_heights(s: &str) -> Result<HashMap<u8, f64>> {
+    s.split_whitespace()
+        .map(|entry| {
+            let (id, height) = entry.split_at(entry.find(':').ok_or(anyhow!("wall height entry without ':': {}", entry))?);
+            let id = match id.as_bytes() {
+                &[id @ b'a'..=b'z'] => id,
+                _ => bail!("wall height id must be a single lowercase letter: {}", id),
+            };
+            let height: f64 = height[1..].parse().with_context(|| format!("reading wall height {}", entry))?;
+            if !(height > 0.0 && height <= 1.0) {
+                bail!("wall height must be within (0, 1], got {}", height);
             }
-        }
-    }
+            Ok((id, height))
+        })
+        .collect()
+}
+
+/// Reads a `BB` header: space-separated `<item id>:<mode>` entries, where
+/// the mode is `V` to face the camera, or `X` or `Y` for a fixed axis.
+fn read_billboards(s: &str) -> Result<HashMap<u8, Billboard>> {
+    s.split_whitespace()
+        .map(|entry| {
+            let (id, mode) = entry.split_at(entry.find(':').ok_or(anyhow!("billboard entry without ':': {}", entry))?);
+            let id = match id.as_bytes() {
+                &[id @ b'2'..=b'9'] => id - b'0',
+                _ => bail!("billboard id must be an item digit 2 to 9: {}", id),
+            };
+            let mode = match &mode[1..] {
+                "V" => Billboard::ViewFacing,
+                "X" => Billboard::AxisX,
+                "Y" => Billboard::AxisY,
+                mode => bail!("billboard mode must be V, X or Y: {}", mode),
+            };
+            Ok((id, mode))
+        })
+        .collect()
+}
+
+fn check_borders(data: &Array2D<MapCell>) -> Result<()> {
+
+    let (h,w) = (data.extents()[0], data.extents()[1]);
+
+    for y in &[0, h-1] {
+        for x in 0..w {
+            if data[[*y,x]].is_walkable() {
+                bail!("Edge cell isn't a wall at ({},{})", y, x);
+            }
+        }
+    }
+
+    for x in &[0, w-1] {
+        for y in 0..h {
+            if data[[y,*x]].is_walkable() {
+                bail!("Edge cell isn't a wall at ({},{})", y, x);
+            }
+        }
+    }
 
     Ok(())
 }
@@ -220,11 +1118,89 @@ fn check_borders(data: &Array2D<MapCell>) -> Result<()> {
 impl Map {
 
     pub fn load<R: BufRead>(source: R) -> Result<Self> {
+        Self::load_with_options(source, &LoadOptions::default())
+    }
+
+    pub fn load_with_options<R: BufRead>(source: R, options: &LoadOptions) -> Result<Self> {
+        Self::parse(source, options, |path| Texture::load(path), &mut |_, _| ())
+    }
+
+    /// Like `load`, calling `on_progress(loaded, total)` after decoding each
+    /// of the `total` textures the map declares.
+    pub fn load_with_progress<R: BufRead>(source: R, mut on_progress: impl FnMut(usize, usize)) -> Result<Self> {
+        Self::parse(source, &LoadOptions::default(), |path| Texture::load(path), &mut on_progress)
+    }
 
+    /// Loads a map whose textures are only decoded when first rendered, for
+    /// maps referencing many textures. See `Texture::lazy`.
+    pub fn load_lazy<R: BufRead>(source: R) -> Result<Self> {
+        Self::load_with(source, |path| Texture::lazy(path))
+    }
+
+    /// Loads a map, using `texture` to resolve the texture paths found in
+    /// the headers instead of reading them from the filesystem.
+    pub fn load_with<R, F>(source: R, texture: F) -> Result<Self>
+        where R: BufRead,
+              F: FnMut(&str) -> Result<Texture>
+    {
+        Self::parse(source, &LoadOptions::default(), texture, &mut |_, _| ())
+    }
+
+    /// Builds a single level map from its parts rather than from a `.cub`
+    /// file, checked like `load` checks the maps it reads: the map must be
+    /// closed by walls and the spawn point walkable. The optional headers
+    /// are left unset.
+    pub fn from_parts(data: Array2D<MapCell>, spawn: Spawn, textures: [Texture; 4], sprite: Texture,
+                      floor: RGB, ceiling: RGB, resolution: (usize, usize)) -> Result<Self> {
+        if spawn.level != 0 {
+            bail!("spawn level {} of a single level map", spawn.level);
+        }
+        check_borders(&data)?;
+        let (h, w) = (data.extents()[0], data.extents()[1]);
+        if (0..h).any(|y| (0..w).any(|x| data[[y, x]] == MapCell::Window)) {
+            bail!("window cells without a G texture");
+        }
+
+        let map = Self {
+            resolution,
+            fov: None,
+            textures,
+            sprite: sprite.with_wrap(WrapMode::Clamp),
+            sprites: HashMap::new(),
+            billboards: HashMap::new(),
+            door: None,
+            window: None,
+            tints: HashMap::new(),
+            emissive: HashSet::new(),
+            wall_heights: HashMap::new(),
+            floor,
+            ceiling,
+            ceiling_gradient: None,
+            floor_gradient: None,
+            data,
+            levels: Vec::new(),
+            spawn,
+            wrap: false,
+        };
+        Spawn::new_checked(&map, map.spawn.x, map.spawn.y, map.spawn.direction)?;
+        Ok(map)
+    }
+
+    /// Reads only the headers of a map, by name.
+    pub fn read_headers<R: BufRead>(source: R) -> Result<HashMap<String, String>> {
+        headers(&mut clean_lines(source).peekable())
+    }
 
-        let mut lines = source.lines().peekable();
+    pub(crate) fn parse<R, F>(source: R, options: &LoadOptions, mut texture: F, progress: &mut dyn FnMut(usize, usize)) -> Result<Self>
+        where R: BufRead,
+              F: FnMut(&str) -> Result<Texture>
+    {
+        let mut lines = clean_lines(source).peekable();
         let h = headers(&mut lines)?;
 
+        let total = texture_headers().filter(|k| h.contains_key(k)).count();
+        let mut loaded = 0;
+
         let resolution = {
             let rs = h.get("R").ok_or(anyhow!("R header missing"))?;
             let xy: Vec<_> = rs.split(' ').collect();
@@ -234,30 +1210,238 @@ impl Map {
             (xy[0].parse()?, xy[1].parse()?)
         };
 
+        let mut texture = |spec: &str| {
+            let (path, transforms) = texture_spec(spec);
+            let tex = if path.starts_with("data:") {
+                Texture::from_bytes(&data_url(path)?)?
+            } else {
+                texture(path)?
+            };
+            let tex = tex.with_transforms(&transforms).with_source(spec);
+            loaded += 1;
+            progress(loaded, total);
+            Ok::<_, anyhow::Error>(tex)
+        };
+
         let textures = [
-            Texture::load(h.get("NO").ok_or(anyhow!("NO texture missing"))?).context("loading NO texture")?,
-            Texture::load(h.get("SO").ok_or(anyhow!("SO texture missing"))?).context("loading SO texture")?,
-            Texture::load(h.get("WE").ok_or(anyhow!("WE texture missing"))?).context("loading WE texture")?,
-            Texture::load(h.get("EA").ok_or(anyhow!("EA texture missing"))?).context("loading EA texture")?,
+            texture(h.get("NO").ok_or(anyhow!("NO texture missing"))?).context("loading NO texture")?,
+            texture(h.get("SO").ok_or(anyhow!("SO texture missing"))?).context("loading SO texture")?,
+            texture(h.get("WE").ok_or(anyhow!("WE texture missing"))?).context("loading WE texture")?,
+            texture(h.get("EA").ok_or(anyhow!("EA texture missing"))?).context("loading EA texture")?,
         ];
 
-        let sprite = Texture::load(h.get("S").ok_or(anyhow!("S texture missing"))?)?;
+        let sprite = texture(h.get("S").ok_or(anyhow!("S texture missing"))?)?
+            .with_wrap(WrapMode::Clamp);
 
-        let floor = read_rgb(h.get("F").ok_or(anyhow!("no floor color"))?)?;
-        let ceiling = read_rgb(h.get("C").ok_or(anyhow!("no ceiling color"))?)?;
+        let mut sprites = HashMap::new();
+        for id in 2..=9 {
+            let key = format!("S{}", id);
+            if let Some(spec) = h.get(&key) {
+                let sprite = texture(spec).with_context(|| format!("loading {} texture", key))?;
+                sprites.insert(id, sprite.with_wrap(WrapMode::Clamp));
+            }
+        }
 
-        let (data, spawn) = load_map(lines)?;
+        let door = match h.get("D") {
+            Some(spec) => Some(texture(spec).context("loading D texture")?),
+            None => None,
+        };
 
-        check_borders(&data)?;
+        let window = match h.get("G") {
+            Some(spec) => Some(texture(spec).context("loading G texture")?),
+            None => None,
+        };
+
+        let tints = match h.get("TINT") {
+            Some(palette) => read_palette(palette).context("reading TINT header")?,
+            None => HashMap::new(),
+        };
+
+        let emissive = match h.get("GLOW") {
+            Some(tags) => read_tags(tags).context("reading GLOW header")?,
+            None => HashSet::new(),
+        };
+
+        let wall_heights = match h.get("WH") {
+            Some(heights) => read_heights(heights).context("reading WH header")?,
+            None => HashMap::new(),
+        };
+
+        let billboards = match h.get("BB") {
+            Some(modes) => read_billboards(modes).context("reading BB header")?,
+            None => HashMap::new(),
+        };
+
+        let fov = match h.get("FOV") {
+            Some(fov) => {
+                let fov: f64 = fov.parse().context("reading FOV header")?;
+                if !(fov > 0.0 && fov < 90.0) {
+                    bail!("FOV header must be within (0, 90) degrees, got {}", fov);
+                }
+                Some(fov)
+            }
+            None => None,
+        };
+
+        let floor = match h.get("F") {
+            Some(rgb) => read_rgb(rgb)?,
+            None if options.strict => bail!("no floor color"),
+            None => DEFAULT_FLOOR,
+        };
+        let ceiling = match h.get("C") {
+            Some(rgb) => read_rgb(rgb)?,
+            None if options.strict => bail!("no ceiling color"),
+            None => DEFAULT_CEILING,
+        };
+        let ceiling_gradient = match h.get("CG") {
+            Some(colors) => Some(read_gradient(colors).context("reading CG header")?),
+            None => None,
+        };
+        let floor_gradient = match h.get("FG") {
+            Some(colors) => Some(read_gradient(colors).context("reading FG header")?),
+            None => None,
+        };
+
+        let (mut levels, spawn) = load_map(lines, options)?;
+
+        if let Some(size) = h.get("M") {
+            let wh: Vec<_> = size.split(' ').collect();
+            if wh.len() != 2 {
+                bail!("M header: two fields expected");
+            }
+            let (width, height): (usize, usize) = (wh[0].parse()?, wh[1].parse()?);
+            for (n, level) in levels.iter().enumerate() {
+                let grid = grid_of(level);
+                if (grid.width, grid.height) != (width, height) {
+                    bail!("level {} is {}x{} but the M header declares {}x{}",
+                          n, grid.width, grid.height, width, height);
+                }
+            }
+        }
+
+        if !options.wrap {
+            for level in &levels {
+                check_borders(level)?;
+            }
+        }
+
+        let has_windows = levels.iter().any(|level| {
+            let (h,w) = (level.extents()[0], level.extents()[1]);
+            (0..h).any(|y| (0..w).any(|x| level[[y, x]] == MapCell::Window))
+        });
+        if has_windows && window.is_none() {
+            bail!("window cells without a G texture");
+        }
+
+        let data = levels.remove(0);
         
 
         Ok(Self {
             resolution,
+            fov,
             textures,
             sprite,
+            sprites,
+            billboards,
+            door,
+            window,
+            tints,
+            emissive,
+            wall_heights,
             floor,
             ceiling,
-            data, spawn
+            ceiling_gradient,
+            floor_gradient,
+            data, levels, spawn,
+            wrap: options.wrap,
+        })
+    }
+}
+
+#[cfg(feature = "zip")]
+impl Map {
+
+    /// Loads a map from a zip archive containing a single `.cub` file and
+    /// the textures it references. Texture paths are resolved relative to
+    /// the location of the `.cub` entry within the archive.
+    pub fn load_zip<P: AsRef<Path>>(archive: P) -> Result<Self> {
+        let mut zip = zip::ZipArchive::new(File::open(archive)?)?;
+
+        let cub: Vec<String> = zip.file_names()
+            .filter(|name| name.ends_with(".cub"))
+            .map(|name| name.to_owned())
+            .collect();
+
+        let cub = match &cub[..] {
+            [cub] => cub.clone(),
+            [] => bail!("no .cub file in archive"),
+            _ => bail!("more than one .cub file in archive"),
+        };
+
+        let mut source = String::new();
+        zip.by_name(&cub)?.read_to_string(&mut source)?;
+
+        let base = match cub.rfind('/') {
+            Some(i) => &cub[..=i],
+            None => "",
+        };
+
+        Self::load_with(source.as_bytes(), |path| {
+            let name = format!("{}{}", base, path);
+            let mut data = Vec::new();
+            zip.by_name(&name)
+                .with_context(|| format!("{} not found in archive", name))?
+                .read_to_end(&mut data)?;
+            Texture::from_bytes(&data)
+        }).context("loading map from archive")
+    }
+}
+
+/// Textures decoded ahead of loading a map by `Map::prefetch_textures`, by
+/// path, one per header referencing the path.
+#[cfg(feature = "prefetch")]
+pub struct PrefetchedTextures {
+    textures: HashMap<String, Vec<Texture>>,
+}
+
+#[cfg(feature = "prefetch")]
+impl Map {
+
+    /// Starts reading and decoding the textures referenced by `headers`, as
+    /// returned by `read_headers`, one thread per texture, so that a level
+    /// can be loaded while another plays. Join the handle and pass the
+    /// textures to `load_prefetched`.
+    pub fn prefetch_textures(headers: &HashMap<String, String>) -> JoinHandle<Result<PrefetchedTextures>> {
+        let paths: Vec<String> = texture_headers()
+            .filter_map(|k| headers.get(&k))
+            .map(|spec| texture_spec(spec).0.to_owned())
+            .filter(|path| !path.starts_with("data:"))
+            .collect();
+
+        std::thread::spawn(move || {
+            let decoded: Vec<_> = std::thread::scope(|scope| {
+                let workers: Vec<_> = paths.iter()
+                    .map(|path| scope.spawn(move || Texture::load(path).with_context(|| format!("prefetching {}", path))))
+                    .collect();
+                workers.into_iter().map(|worker| worker.join().expect("prefetch thread panicked")).collect()
+            });
+
+            let mut textures: HashMap<String, Vec<Texture>> = HashMap::new();
+            for (path, texture) in paths.into_iter().zip(decoded) {
+                textures.entry(path).or_default().push(texture?);
+            }
+            Ok(PrefetchedTextures { textures })
+        })
+    }
+
+    /// Like `load`, using the textures of `prefetched` rather than reading
+    /// them again. Textures it lacks are loaded as `load` does.
+    pub fn load_prefetched<R: BufRead>(source: R, mut prefetched: PrefetchedTextures) -> Result<Self> {
+        Self::load_with(source, |path| {
+            match prefetched.textures.get_mut(path).and_then(Vec::pop) {
+                Some(texture) => Ok(texture),
+                None => Texture::load(path),
+            }
         })
     }
 }
@@ -285,18 +1469,185 @@ C 225,30,0
     let mut expected_data = Array2D::new([4,4], MapCell::Wall);
 
     expected_data[[1,2]] = MapCell::Space;
-    expected_data[[2,1]] = MapCell::Item;
+    expected_data[[2,1]] = MapCell::Item(2);
     expected_data[[2,2]] = MapCell::Space;
 
     assert_eq!(m.resolution, (640, 480));
     assert_eq!(m.floor, Rgb([220, 100, 0]));
     assert_eq!(m.ceiling, Rgb([225, 30, 0]));
-    assert_eq!(m.spawn, Spawn { direction: Direction::N, x: 2, y: 2});
+    assert_eq!(m.spawn, Spawn { direction: Direction::N, x: 2, y: 2, level: 0 });
     assert!(m.data == expected_data);
 
 
 }
 
+#[test]
+fn test_loader_bom_crlf() {
+    let lf = std::fs::read_to_string("sample.cub").unwrap();
+    let crlf = format!("\u{FEFF}{}", lf.replace('\n', "\r\n"));
+
+    let expected = Map::load(lf.as_bytes()).unwrap();
+    let m = Map::load(crlf.as_bytes()).unwrap();
+
+    assert_eq!(m.resolution, expected.resolution);
+    assert_eq!(m.floor, expected.floor);
+    assert_eq!(m.ceiling, expected.ceiling);
+    assert_eq!(m.spawn, expected.spawn);
+    assert!(m.data == expected.data);
+}
+
+#[test]
+fn test_unreachable_items() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+F 220,100,0
+C 225,30,0
+
+111111
+1N0121
+120111
+111111
+";
+    let m = Map::load(&data[..]).unwrap();
+
+    assert_eq!(m.unreachable_items(), vec![(4, 1)]);
+}
+
+#[test]
+fn test_grid_distance() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let m = Map::load(&data[..]).unwrap();
+    let spawn = (m.spawn.x, m.spawn.y);
+    assert_eq!(spawn, (20, 11));
+    assert_eq!(m.data[[9, 4]], MapCell::Item(2));
+
+    // North up column 18 to row 3, west along it to column 9, down to row
+    // 5, west to column 4 and down to the item.
+    assert_eq!(m.grid_distance(spawn, (4, 9)), Some(30));
+    assert_eq!(m.grid_distance((4, 9), spawn), Some(30));
+    assert_eq!(m.grid_distance(spawn, spawn), Some(0));
+    assert_eq!(m.grid_distance(spawn, (20, 10)), Some(1));
+
+    assert_eq!(m.grid_distance(spawn, (0, 0)), None);
+    assert_eq!(m.grid_distance(spawn, (100, 1)), None);
+
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+11111
+1N101
+11111
+";
+    let m = Map::load(&data[..]).unwrap();
+    assert_eq!(m.grid_distance((1, 1), (3, 1)), None);
+}
+
+#[test]
+fn test_load_inverted() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+F 220,100,0
+C 225,30,0
+
+      
+ 0101 
+ 1N20 
+ 0011 
+      
+";
+    let plain = Map::load(&data[..]).unwrap();
+    let inverted = Map::load_with_options(&data[..], &LoadOptions { invert: true, ..Default::default() }).unwrap();
+
+    assert_eq!(plain.spawn, inverted.spawn);
+
+    for y in 1..4 {
+        for x in 1..5 {
+            match (plain.data[[y,x]], inverted.data[[y,x]]) {
+                (MapCell::Space, MapCell::Wall) | (MapCell::Wall, MapCell::Space) => (),
+                (a, b) if (x,y) == (2,2) || (x,y) == (3,2) => assert!(a == b),
+                _ => panic!("cell ({},{}) not inverted", x, y),
+            }
+        }
+        assert!(plain.data[[y,0]] == MapCell::Wall && inverted.data[[y,0]] == MapCell::Wall);
+    }
+}
+
+#[test]
+fn test_load_levels() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+F 220,100,0
+C 225,30,0
+
+1111
+1001
+1111
+LEVEL
+11111
+10201
+10E01
+11111
+";
+    let m = Map::load(&data[..]).unwrap();
+
+    assert_eq!(m.data.extents(), &[3, 4]);
+    assert_eq!(m.levels.len(), 1);
+    assert_eq!(m.levels[0].extents(), &[4, 5]);
+    assert_eq!(m.spawn, Spawn { direction: Direction::E, x: 2, y: 2, level: 1 });
+    assert!(m.level(1).unwrap()[[1,2]] == MapCell::Item(2));
+    assert!(m.level(2).is_none());
+}
+
+#[test]
+fn test_item_sprites() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+S3 tex/fixtures/rgb2x2.png
+F 220,100,0
+C 225,30,0
+
+11111
+1N231
+11111
+";
+    let m = Map::load(&data[..]).unwrap();
+
+    let cell = m.data[[1,3]];
+    assert!(cell == MapCell::Item(3));
+
+    if let MapCell::Item(id) = cell {
+        assert!(std::ptr::eq(m.sprite_for(id), &m.sprites[&3]));
+        assert_eq!(m.sprite_for(id).get((0.0, 0.0)), Rgb([255, 0, 0]));
+    }
+
+    assert!(std::ptr::eq(m.sprite_for(2), &m.sprite));
+}
+
 #[test]
 fn test_map_edge() {
     let data = b"
@@ -317,4 +1668,631 @@ C 225,30,0
 ";
     let m = Map::load(&data[..]);
     assert!(m.is_err());
-}
\ No newline at end of file
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_load_zip() {
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join("rustic-raycaster-test-map.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+    let options = zip::write::FileOptions::default();
+
+    zip.start_file("maps/sample.cub", options).unwrap();
+    zip.write_all(&std::fs::read("sample.cub").unwrap()).unwrap();
+
+    for tex in &["north", "south", "west", "east", "sprite"] {
+        zip.start_file(format!("maps/tex/{}.png", tex), options).unwrap();
+        zip.write_all(&std::fs::read(format!("tex/{}.png", tex)).unwrap()).unwrap();
+    }
+    zip.finish().unwrap();
+
+    let m = Map::load_zip(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let plain = Map::load(&std::fs::read("sample.cub").unwrap()[..]).unwrap();
+    assert_eq!(m.resolution, plain.resolution);
+    assert_eq!(m.spawn, plain.spawn);
+    assert!(m.data == plain.data);
+}
+
+#[cfg(feature = "prefetch")]
+#[test]
+fn test_prefetch_textures() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let headers = Map::read_headers(&data[..]).unwrap();
+    let prefetched = Map::prefetch_textures(&headers).join().unwrap().unwrap();
+    let m = Map::load_prefetched(&data[..], prefetched).unwrap();
+    let plain = Map::load(&data[..]).unwrap();
+
+    assert!(m.textures.iter().chain(Some(&m.sprite)).all(Texture::is_decoded));
+    assert_eq!((m.resolution, m.floor, m.ceiling), (plain.resolution, plain.floor, plain.ceiling));
+    assert_eq!(m.spawn, plain.spawn);
+    assert!(m.data == plain.data);
+    for (a, b) in m.textures.iter().chain(Some(&m.sprite)).zip(plain.textures.iter().chain(Some(&plain.sprite))) {
+        assert_eq!(a.source(), b.source());
+        assert_eq!(a.dimensions(), b.dimensions());
+        assert_eq!(a.get((0.3, 0.7)), b.get((0.3, 0.7)));
+    }
+
+    let mut missing = headers.clone();
+    missing.insert("NO".to_string(), "tex/missing.png".to_string());
+    assert!(Map::prefetch_textures(&missing).join().unwrap().is_err());
+
+    // Textures left out of the prefetch fail to load as with `load`.
+    let prefetched = Map::prefetch_textures(&headers).join().unwrap().unwrap();
+    let source = String::from_utf8(data).unwrap().replacen(&format!("NO {}", headers["NO"]), "NO tex/missing.png", 1);
+    assert!(Map::load_prefetched(source.as_bytes(), prefetched).is_err());
+}
+
+#[test]
+fn test_default_colors() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+111
+1N1
+111
+";
+    let m = Map::load(&data[..]).unwrap();
+    assert_eq!(m.floor, DEFAULT_FLOOR);
+    assert_eq!(m.ceiling, DEFAULT_CEILING);
+
+    let strict = LoadOptions { strict: true, ..Default::default() };
+    assert!(Map::load_with_options(&data[..], &strict).is_err());
+}
+
+#[test]
+fn test_visibility() {
+    use crate::geometry::v;
+
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+1111111
+1N00001
+1000001
+1001111
+1000001
+1000001
+1111111
+";
+    let m = Map::load(&data[..]).unwrap();
+    let eye = v(1.5, 1.5);
+
+    assert_eq!(m.visibility(eye, v(1.5, 4.5), 9), 1.0);
+    assert_eq!(m.visibility(eye, v(5.5, 4.5), 9), 0.0);
+
+    let corner = m.visibility(eye, v(3.0, 4.5), 9);
+    assert!(corner > 0.0 && corner < 1.0, "corner visibility {}", corner);
+
+    // The wall behind a target doesn't block the view of it.
+    assert!(m.line_of_sight(eye, v(4.5, 1.5)));
+    assert!(!m.line_of_sight(eye, v(4.5, 3.5)));
+}
+
+#[test]
+fn test_get_set_cell() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+1111
+1N01
+1111
+";
+    let mut m = Map::load(&data[..]).unwrap();
+
+    assert_eq!(m.get_cell(2, 1), Some(MapCell::Space));
+    assert_eq!(m.get_cell(3, 2), Some(MapCell::Wall));
+    assert_eq!(m.get_cell(4, 1), None);
+    assert_eq!(m.get_cell(1, 3), None);
+
+    assert!(m.set_cell(2, 1, MapCell::Item(2)));
+    assert_eq!(m.get_cell(2, 1), Some(MapCell::Item(2)));
+    assert!(!m.is_walkable(4, 1));
+    assert!(!m.set_cell(4, 1, MapCell::Space));
+}
+
+#[test]
+fn test_render_ascii() {
+    let m = Map::load(&std::fs::read("sample.cub").unwrap()[..]).unwrap();
+
+    let plain = m.render_ascii(false);
+    let lines: Vec<&str> = plain.lines().collect();
+    assert_eq!(lines[0], "map layout: 14x33");
+    assert_eq!(&lines[10][4..5], "*");
+    assert_eq!(&lines[12][20..21], "^");
+    assert!(lines[15].ends_with(" items, 0 fog"));
+
+    let ids = m.render_ascii(true);
+    assert_eq!(&ids.lines().nth(10).unwrap()[4..5], "2");
+
+    // Windows and doors are counted apart from walls.
+    let mut m = m;
+    let summary = |m: &Map| m.render_ascii(false).lines().nth(15).unwrap().to_string();
+    let before = summary(&m);
+    assert!(before.contains(" walls, 0 windows, 0 doors, "), "{}", before);
+    assert!(m.set_cell(0, 1, MapCell::Window) && m.set_cell(0, 2, MapCell::Door(0)));
+    let after = summary(&m);
+    let count = |line: &str| line.split(' ').next().unwrap().parse::<usize>().unwrap();
+    assert_eq!(count(&after), count(&before) - 2);
+    assert!(after.contains(" walls, 1 windows, 1 doors, "), "{}", after);
+}
+
+#[test]
+fn test_load_lazy() {
+    let m = Map::load_lazy(&std::fs::read("sample.cub").unwrap()[..]).unwrap();
+    assert!(m.textures.iter().all(|t| !t.is_decoded()));
+
+    let tex = m.texture(Direction::N);
+    assert_eq!(tex.height(), 128);
+    assert!(tex.is_decoded());
+    assert!(!m.texture(Direction::S).is_decoded());
+}
+
+#[test]
+fn test_texture_modifiers() {
+    assert_eq!(texture_spec("tex/a.png"), ("tex/a.png", vec![]));
+    assert_eq!(texture_spec("tex/a b.png flipx rot90"), ("tex/a b.png", vec![Transform::FlipX, Transform::Rot90]));
+
+    let data = b"
+R 640 480
+NO tex/north.png flipx
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+111
+1N1
+111
+";
+    let m = Map::load(&data[..]).unwrap();
+    let original = Texture::load("tex/north.png").unwrap();
+    assert_eq!(m.texture(Direction::N).get((0.0, 0.0)), original.get((127.0 / 128.0, 0.0)));
+}
+
+#[test]
+fn test_map_size_header() {
+    let map = |size: &str| format!("
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+M {}
+
+1111
+1N01
+111
+", size);
+
+    assert!(Map::load(map("4 3").as_bytes()).is_ok());
+
+    let err = Map::load(map("4 4").as_bytes()).unwrap_err();
+    assert_eq!(err.to_string(), "level 0 is 4x3 but the M header declares 4x4");
+    assert!(Map::load(map("5 3").as_bytes()).is_err());
+    assert!(Map::load(map("4").as_bytes()).is_err());
+}
+
+#[test]
+fn test_resize() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+1111
+1N01
+1021
+1111
+";
+    let mut m = Map::load(&data[..]).unwrap();
+    let cells = |m: &Map| (0..4).flat_map(|y| (0..4).map(move |x| (x, y)))
+        .map(|(x, y)| m.get_cell(x, y))
+        .collect::<Vec<_>>();
+    let original = cells(&m);
+
+    m.resize(6, 6).unwrap();
+    assert_eq!(m.data.extents(), [6, 6]);
+    assert_eq!(cells(&m), original);
+    for i in 0..6 {
+        assert!(m.get_cell(i, 5) == Some(MapCell::Wall) && m.get_cell(5, i) == Some(MapCell::Wall));
+    }
+
+    // Cropping would expose floor cells on the border.
+    assert!(m.resize(3, 3).is_err());
+    assert!(m.resize(1, 1).is_err());
+    assert_eq!(m.data.extents(), [6, 6]);
+
+    // Cropping behind an inner wall would drop the floor past it.
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+1111111
+1N01001
+1111111
+";
+    let mut m = Map::load(&data[..]).unwrap();
+    assert!(m.resize(4, 3).is_err());
+    assert_eq!(m.data.extents(), [3, 7]);
+    m.resize(7, 4).unwrap();
+}
+
+#[test]
+fn test_load_with_progress() {
+    let mut calls = Vec::new();
+    let data = std::fs::read("sample.cub").unwrap();
+    Map::load_with_progress(&data[..], |loaded, total| calls.push((loaded, total))).unwrap();
+
+    assert_eq!(calls, vec![(1, 5), (2, 5), (3, 5), (4, 5), (5, 5)]);
+}
+
+#[test]
+fn test_fov_header() {
+    let map = |fov: &str| format!("
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+{}
+
+111
+1N1
+111
+", fov);
+
+    assert_eq!(Map::load(map("").as_bytes()).unwrap().fov, None);
+    assert_eq!(Map::load(map("FOV 60").as_bytes()).unwrap().fov, Some(60.0));
+    assert!(Map::load(map("FOV 0").as_bytes()).is_err());
+    assert!(Map::load(map("FOV 120").as_bytes()).is_err());
+    assert!(Map::load(map("FOV wide").as_bytes()).is_err());
+}
+
+#[test]
+fn test_wrap() {
+    let data = "
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+1111111
+0100N00
+1111111
+";
+    assert!(Map::load(data.as_bytes()).is_err());
+
+    let map = Map::load_with_options(data.as_bytes(), &LoadOptions { wrap: true, ..Default::default() }).unwrap();
+    assert!(map.wrap);
+    assert!(map.is_walkable(7, 1));
+    assert!(!map.is_walkable(8, 1));
+
+    // The west end of the corridor is reached through the east edge.
+    let reachable = map.reachable();
+    assert!(reachable[[1, 0]] && reachable[[1, 6]]);
+    assert!(!reachable[[1, 1]]);
+}
+
+#[test]
+fn test_write_cub() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let mut map = Map::load(&data[..]).unwrap();
+    assert!(map.set_cell(1, 1, MapCell::Fog));
+
+    let mut out = Vec::new();
+    map.write_cub(&mut out).unwrap();
+    let reloaded = Map::load(&out[..]).unwrap();
+
+    assert!(reloaded.data == map.data);
+    assert_eq!(reloaded.spawn, map.spawn);
+    assert_eq!((reloaded.resolution, reloaded.floor, reloaded.ceiling), (map.resolution, map.floor, map.ceiling));
+    assert_eq!(reloaded.textures[0].source(), Some("tex/north.png"));
+
+    // Writing is stable once round-tripped.
+    let mut again = Vec::new();
+    reloaded.write_cub(&mut again).unwrap();
+    assert_eq!(String::from_utf8(again).unwrap(), String::from_utf8(out).unwrap());
+
+    // Grid rows may start with a letter.
+    assert!(map.set_cell(0, 0, MapCell::TaggedWall(b'a')));
+    let mut out = Vec::new();
+    map.write_cub(&mut out).unwrap();
+    let reloaded = Map::load(&out[..]).unwrap();
+    assert!(reloaded.data == map.data);
+}
+
+#[test]
+fn test_item_ids() {
+    let data = "
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+11111
+1N231
+11111
+";
+    let map = Map::load(data.as_bytes()).unwrap();
+    assert_eq!(map.get_cell(2, 1), Some(MapCell::Item(2)));
+    assert_eq!(map.get_cell(3, 1), Some(MapCell::Item(3)));
+    assert!(format!("{:?}", map).contains("#^23#"));
+}
+
+#[test]
+fn test_wall_textures() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let map = Map::load(&data[..]).unwrap();
+
+    let textures: Vec<_> = map.wall_textures().collect();
+    let directions: Vec<_> = textures.iter().map(|&(d, _)| d).collect();
+    assert_eq!(directions, vec![Direction::N, Direction::S, Direction::W, Direction::E]);
+    for (d, texture) in textures {
+        assert!(std::ptr::eq(texture, map.texture(d)));
+    }
+    assert_eq!(map.wall_textures().nth(2).unwrap().1.source(), Some("tex/west.png"));
+}
+
+#[test]
+fn test_data_url_texture() {
+    let red = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAIAAACQd1PeAAAADElEQVR42mP4z8AAAAMBAQD3A0FDAAAAAElFTkSuQmCC";
+    let data = format!("
+R 640 480
+NO {}
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+111
+1N1
+111
+", red);
+    let map = Map::load(data.as_bytes()).unwrap();
+    assert_eq!(map.texture(Direction::N).get((0.5, 0.5)), Rgb([255, 0, 0]));
+    assert_eq!(map.texture(Direction::N).source(), Some(red));
+
+    assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+    assert!(Map::load(data.replace(";base64", "").as_bytes()).is_err());
+}
+
+#[test]
+fn test_walkable_area() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let mut map = Map::load(&data[..]).unwrap();
+
+    // 201 spaces, the spawn point and one item.
+    assert_eq!(map.walkable_area(), 203);
+    assert_eq!(map.item_density(), 1.0 / 203.0);
+
+    assert!(map.set_cell(1, 1, MapCell::Door(0)));
+    assert_eq!(map.walkable_area(), 202);
+    assert!(map.set_cell(1, 1, MapCell::Door(DOOR_OPEN)));
+    assert_eq!(map.walkable_area(), 203);
+}
+
+#[test]
+fn test_diff() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let map = Map::load(&data[..]).unwrap();
+    let mut edited = Map::load(&data[..]).unwrap();
+
+    let same = map.diff(&edited).unwrap();
+    assert_eq!(same, MapDiff { cells: vec![], spawn: false, headers: false });
+
+    assert!(edited.set_cell(3, 1, MapCell::Fog));
+    let diff = map.diff(&edited).unwrap();
+    assert_eq!(diff.cells, vec![CellChange { level: 0, x: 3, y: 1, from: MapCell::Space, to: MapCell::Fog }]);
+    assert!(!diff.spawn && !diff.headers);
+
+    edited.floor = Rgb([1, 2, 3]);
+    edited.spawn.direction = Direction::W;
+    let diff = map.diff(&edited).unwrap();
+    assert!(diff.spawn && diff.headers);
+
+    edited.resize(40, 20).unwrap();
+    assert!(map.diff(&edited).is_err());
+}
+
+#[test]
+fn test_grid_view() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let map = Map::load(&data[..]).unwrap();
+    let view = map.grid_view();
+
+    assert_eq!(view.get(2, 1), Some(MapCell::Space));
+    assert_eq!(view.get(0, 0), Some(MapCell::Wall));
+    assert_eq!(view.get(view.width(), 0), None);
+    assert_eq!((view.width(), view.height()), (map.data.extents()[1], map.data.extents()[0]));
+
+    let rows: Vec<Vec<_>> = view.rows().map(Iterator::collect).collect();
+    assert_eq!(rows.len(), view.height());
+    assert!(rows.iter().all(|row| row.len() == view.width()));
+    assert_eq!(rows[1][2], MapCell::Space);
+}
+
+#[test]
+fn test_spawn_new_checked() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let map = Map::load(&data[..]).unwrap();
+
+    assert_eq!(Spawn::new_checked(&map, 2, 1, Direction::E).unwrap(),
+               Spawn { direction: Direction::E, x: 2, y: 1, level: 0 });
+
+    let wall = Spawn::new_checked(&map, 0, 0, Direction::E).unwrap_err();
+    assert_eq!(wall.to_string(), "spawn point (0, 0) is in a Wall cell");
+
+    let outside = Spawn::new_checked(&map, 100, 1, Direction::E).unwrap_err();
+    assert!(outside.to_string().starts_with("spawn point (100, 1) outside the "), "{}", outside);
+}
+
+#[test]
+fn test_cell_flags() {
+    use CellFlags as F;
+    let solid = F::OPAQUE | F::BLOCKS_MOVEMENT | F::BLOCKS_SIGHT;
+    assert_eq!(MapCell::Space.flags(), F::NONE);
+    assert_eq!(MapCell::Item(2).flags(), F::NONE);
+    assert_eq!(MapCell::Wall.flags(), solid);
+    assert_eq!(MapCell::TaggedWall(b'a').flags(), solid);
+    assert_eq!(MapCell::Door(0).flags(), solid);
+    assert_eq!(MapCell::Door(128).flags(), solid);
+    assert_eq!(MapCell::Door(DOOR_OPEN).flags(), F::NONE);
+    assert_eq!(MapCell::Fog.flags(), F::OPAQUE | F::BLOCKS_SIGHT);
+    assert_eq!(MapCell::Window.flags(), F::BLOCKS_MOVEMENT);
+
+    assert!(solid.contains(F::OPAQUE | F::BLOCKS_SIGHT) && solid.contains(F::NONE));
+    assert!(!F::OPAQUE.contains(F::BLOCKS_SIGHT));
+    assert!(MapCell::Fog.is_walkable() && MapCell::Fog.blocks_sight());
+    assert!(!MapCell::Window.is_walkable() && !MapCell::Window.blocks_sight());
+}
+
+#[test]
+fn test_billboards() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+BB 2:X 3:Y 4:V
+
+111111
+1N2341
+111111
+";
+    let m = Map::load(&data[..]).unwrap();
+    assert_eq!(m.billboard_for(2), Billboard::AxisX);
+    assert_eq!(m.billboard_for(3), Billboard::AxisY);
+    assert_eq!(m.billboard_for(4), Billboard::ViewFacing);
+    assert_eq!(m.billboard_for(5), Billboard::ViewFacing);
+
+    let mut out = Vec::new();
+    m.write_cub(&mut out).unwrap();
+    assert_eq!(Map::load(&out[..]).unwrap().billboards, m.billboards);
+
+    assert!(read_billboards("2:Z").is_err());
+    assert!(read_billboards("1:X").is_err());
+    assert!(read_billboards("2X").is_err());
+}
+
+#[test]
+fn test_wall_heights() {
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+WH a:0.5 b:1
+
+11111
+1Nab1
+11111
+";
+    let m = Map::load(&data[..]).unwrap();
+    assert_eq!(m.wall_height(MapCell::TaggedWall(b'a')), 0.5);
+    assert_eq!(m.wall_height(MapCell::TaggedWall(b'b')), 1.0);
+    assert_eq!(m.wall_height(MapCell::TaggedWall(b'c')), 1.0);
+    assert_eq!(m.wall_height(MapCell::Wall), 1.0);
+
+    let mut out = Vec::new();
+    m.write_cub(&mut out).unwrap();
+    assert_eq!(Map::load(&out[..]).unwrap().wall_heights, m.wall_heights);
+
+    assert!(read_heights("a:0").is_err());
+    assert!(read_heights("a:1.5").is_err());
+    assert!(read_heights("A:0.5").is_err());
+}
+
+#[test]
+fn test_from_parts() {
+    let parts = |data| {
+        let tex = || Texture::from_noise(1, Rgb([128, 128, 128]), 16, 4);
+        Map::from_parts(data, Spawn { direction: Direction::E, x: 1, y: 1, level: 0 },
+                        [tex(), tex(), tex(), tex()], tex(), DEFAULT_FLOOR, DEFAULT_CEILING, (64, 48))
+    };
+    let room = || {
+        let mut data = Array2D::new([4, 5], MapCell::Wall);
+        for x in 1..4 {
+            for y in 1..3 {
+                data[[y, x]] = MapCell::Space;
+            }
+        }
+        data
+    };
+
+    let map = parts(room()).unwrap();
+    assert_eq!(map.walkable_area(), 6);
+    assert_eq!(map.spawn.direction, Direction::E);
+
+    let mut hole = room();
+    hole[[0, 2]] = MapCell::Space;
+    assert!(parts(hole).is_err());
+
+    let mut blocked = room();
+    blocked[[1, 1]] = MapCell::Wall;
+    assert!(parts(blocked).is_err());
+}
+
+#[test]
+fn test_first_item_along() {
+    use crate::geometry::v;
+
+    let data = b"
+R 640 480
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+1111111
+1N00201
+1000001
+1010001
+1020001
+1111111
+";
+    let m = Map::load(&data[..]).unwrap();
+    let eye = v(1.5, 1.5);
+
+    assert_eq!(m.first_item_along(eye, v(1.0, 0.0)), Some((4, 1)));
+    assert_eq!(m.first_item_along(eye, v(1.0, 2.0)), None);
+    assert_eq!(m.first_item_along(v(2.5, 2.5), v(0.0, 1.0)), None);
+    assert_eq!(m.first_item_along(v(1.5, 4.5), v(1.0, 0.0)), Some((2, 4)));
+    assert_eq!(m.first_item_along(eye, v(0.0, 1.0)), None);
+}
@@ -1,94 +1,2826 @@
-use std::{fs::File, io::BufReader};
+use std::{collections::HashSet, io::Write, ops::Range, sync::OnceLock};
 
-use crate::{geometry::{Grid, Raycaster}, loader::{Direction, Map, MapCell}};
+use crate::{geometry::{Hit, Raycaster}, loader::{grid_of, Billboard, Direction, Map, MapCell, DOOR_OPEN}, texture::Texture};
 use crate::geometry::{Vector, v};
-use image::{ImageBuffer, RgbImage, Rgb};
+use anyhow::{bail, Result};
+use image::{ImageBuffer, Luma, Rgb, Rgba, RgbImage};
+use multiarray::Array2D;
 
 
 pub struct Render {
     pub pos: Vector,
     pub cam: Vector,
+    /// The map level being rendered.
+    pub level: usize,
     fov: f64,
     pub buffer: RgbImage, 
     vfov: f64,
-    height: f64
+    height: f64,
+    aspect_correction: bool,
+    fog: Option<Fog>,
+    /// Draw the floor as a world-space checkerboard of map cells, to check
+    /// the perspective of the projection.
+    pub debug_floor: bool,
+    /// Fill walls with the `flat_color` of their face instead of sampling
+    /// textures, to tell geometry bugs from texturing ones.
+    pub flat_shading: bool,
+    /// Let the camera stand inside walls, drawing the inside of its cell in
+    /// `BACK_FACE` instead of seeing through it.
+    pub noclip: bool,
+    fisheye_correction: bool,
+    angular_shading: f64,
+    /// How many columns `render` shades before copying them to the buffer.
+    tile_columns: usize,
+    linear_shading: bool,
+    vertical_scale: f64,
+    roll: f64,
+    sky_pitch: f64,
+    time: f64,
+    depth_cue: f64,
+    /// The distances mapped to 0 and 1 by `normalized_depth`.
+    near: f64,
+    far: f64,
 }
 
+/// Exponential distance fog: walls at distance `d` are blended towards
+/// `color` by a factor of `1 - exp(-density * d)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fog {
+    pub color: Rgb<u8>,
+    pub density: f64,
+}
+
+impl Fog {
+    fn factor(&self, distance: f64) -> f64 {
+        1.0 - (-self.density * distance).exp()
+    }
+
+    fn apply(&self, pixel: Rgb<u8>, distance: f64) -> Rgb<u8> {
+        let f = self.factor(distance);
+        let mix = |c: u8, fog: u8| (c as f64 + (fog as f64 - c as f64) * f).round() as u8;
+        Rgb([mix(pixel[0], self.color[0]), mix(pixel[1], self.color[1]), mix(pixel[2], self.color[2])])
+    }
+
+    /// Like `apply`, blending in linear light rather than sRGB values.
+    fn apply_linear(&self, pixel: Rgb<u8>, distance: f64) -> Rgb<u8> {
+        let f = self.factor(distance);
+        let (pixel, fog) = (gamma().linear(pixel), gamma().linear(self.color));
+        gamma().srgb([0, 1, 2].map(|c| pixel[c] + (fog[c] - pixel[c]) * f))
+    }
+}
+
+/// The number of steps of the linear light to sRGB table, enough for the
+/// darkest sRGB values to stay distinct.
+const GAMMA_STEPS: usize = 4096;
+
+/// Lookup tables between sRGB values and linear light in `[0, 1]`.
+struct Gamma {
+    to_linear: [f64; 256],
+    to_srgb: Vec<u8>,
+}
+
+fn gamma() -> &'static Gamma {
+    static GAMMA: OnceLock<Gamma> = OnceLock::new();
+    GAMMA.get_or_init(|| {
+        let decode = |c: f64| if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) };
+        let encode = |l: f64| if l <= 0.0031308 { l * 12.92 } else { 1.055 * l.powf(1.0 / 2.4) - 0.055 };
+        let mut to_linear = [0.0; 256];
+        for (c, l) in to_linear.iter_mut().enumerate() {
+            *l = decode(c as f64 / 255.0);
+        }
+        let to_srgb = (0..GAMMA_STEPS)
+            .map(|i| (encode(i as f64 / (GAMMA_STEPS - 1) as f64) * 255.0).round() as u8)
+            .collect();
+        Gamma { to_linear, to_srgb }
+    })
+}
+
+impl Gamma {
+    fn linear(&self, pixel: Rgb<u8>) -> [f64; 3] {
+        pixel.0.map(|c| self.to_linear[c as usize])
+    }
+
+    fn srgb(&self, linear: [f64; 3]) -> Rgb<u8> {
+        let steps = (GAMMA_STEPS - 1) as f64;
+        Rgb(linear.map(|l| self.to_srgb[(l.clamp(0.0, 1.0) * steps).round() as usize]))
+    }
+}
+
+/// Linearly interpolates from `a` at `t = 0` to `b` at `t = 1`.
+fn blend(a: Rgb<u8>, b: Rgb<u8>, t: f64) -> Rgb<u8> {
+    let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+    Rgb([mix(a[0], b[0]), mix(a[1], b[1]), mix(a[2], b[2])])
+}
+
+/// Converts to hue in `[0, 6)`, saturation and value in `[0, 1]`.
+fn rgb_to_hsv(pixel: Rgb<u8>) -> (f64, f64, f64) {
+    let [r, g, b] = pixel.0.map(|c| c as f64 / 255.0);
+    let max = r.max(g).max(b);
+    let chroma = max - r.min(g).min(b);
+
+    let hue = if chroma == 0.0 {
+        0.0
+    } else if max == r {
+        ((g - b) / chroma).rem_euclid(6.0)
+    } else if max == g {
+        (b - r) / chroma + 2.0
+    } else {
+        (r - g) / chroma + 4.0
+    };
+    let saturation = if max == 0.0 { 0.0 } else { chroma / max };
+
+    (hue, saturation, max)
+}
+
+fn hsv_to_rgb((hue, saturation, value): (f64, f64, f64)) -> Rgb<u8> {
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue.rem_euclid(2.0) - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    Rgb([r, g, b].map(|c| ((c + m) * 255.0).round() as u8))
+}
+
+/// Desaturates and darkens a wall texel seen at `distance`, dividing its
+/// saturation and value by `1 + strength * distance`.
+fn depth_cue(pixel: Rgb<u8>, distance: f64, strength: f64) -> Rgb<u8> {
+    let (hue, saturation, value) = rgb_to_hsv(pixel);
+    let f = 1.0 / (1.0 + strength * distance);
+    hsv_to_rgb((hue, saturation * f, value * f))
+}
+
+/// Collects the optional settings of a `Render`, and validates them all at
+/// once when building it.
+pub struct RenderBuilder<'a> {
+    map: &'a Map,
+    fov: f64,
+    vfov: Option<f64>,
+    eye_height: f64,
+    fog: Option<Fog>,
+}
+
+impl<'a> RenderBuilder<'a> {
+    /// Starts from the defaults of `Render::spawn` for `map`.
+    pub fn from_map(map: &'a Map) -> Self {
+        RenderBuilder { map, fov: default_fov(map), vfov: None, eye_height: 0.6, fog: None }
+    }
+
+    /// The horizontal field of view, in degrees from the view axis to the
+    /// edge of the screen.
+    pub fn fov(mut self, degrees: f64) -> Self {
+        self.fov = degrees;
+        self
+    }
+
+    /// An explicit vertical field of view in degrees, instead of one derived
+    /// from the aspect ratio.
+    pub fn vfov(mut self, degrees: f64) -> Self {
+        self.vfov = Some(degrees);
+        self
+    }
+
+    /// The height of the camera above the floor, in wall heights.
+    pub fn eye_height(mut self, height: f64) -> Self {
+        self.eye_height = height;
+        self
+    }
+
+    pub fn fog(mut self, color: Rgb<u8>, density: f64) -> Self {
+        self.fog = Some(Fog { color, density });
+        self
+    }
+
+    pub fn build(self) -> Result<Render> {
+        if !(self.fov > 0.0 && self.fov < 90.0) {
+            bail!("field of view must be within (0, 90) degrees, got {}", self.fov);
+        }
+        if let Some(vfov) = self.vfov {
+            if !(vfov > 0.0 && vfov < 90.0) {
+                bail!("vertical field of view must be within (0, 90) degrees, got {}", vfov);
+            }
+        }
+        if !(0.0..=1.0).contains(&self.eye_height) {
+            bail!("eye height must be within [0, 1], got {}", self.eye_height);
+        }
+        if let Some(fog) = self.fog {
+            if !(0.0..).contains(&fog.density) {
+                bail!("fog density must not be negative, got {}", fog.density);
+            }
+        }
+
+        let mut r = Render::spawn_with_fov(self.map, self.fov);
+        if let Some(vfov) = self.vfov {
+            r.set_vfov(vfov);
+        }
+        r.height = self.eye_height;
+        r.fog = self.fog;
+        Ok(r)
+    }
+}
+
+/// A 16-bit single channel image of per-pixel wall distances.
+pub type DepthImage = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+/// Depth image units per map cell.
+pub const DEPTH_SCALE: f64 = 1000.0;
+
+/// Depth image value for pixels without a wall.
+pub const DEPTH_NONE: u16 = u16::MAX;
+
+/// A wall hit projected onto a screen column, spanning rows `ceil..floor`.
+struct Column {
+    hit: Hit,
+    depth: f64,
+    /// The unclipped fractional rows of the top and bottom of the wall.
+    top: f64,
+    bottom: f64,
+    ceil: u32,
+    floor: u32,
+    /// Whether this is the inside of the wall cell the camera stands in.
+    back_face: bool,
+}
+
+impl Column {
+    /// Lowers the top of the wall to `height` of a full wall, the wall
+    /// showing the lower part of its texture and the background above it.
+    fn with_height(self, height: f64) -> Self {
+        let top = self.bottom - (self.bottom - self.top) * height;
+        Column { ceil: self.ceil.max(clip(top, self.floor)), ..self }
+    }
+
+    /// The texture coordinates of screen row `y` within the wall band.
+    fn uv(&self, y: u32) -> (f64, f64) {
+        let tx = match self.hit.direction {
+            Direction::S | Direction::W => self.hit.position,
+            Direction::N | Direction::E => 1.0 - self.hit.position
+        };
+        let ty = (y as f64 - self.top) / (self.bottom - self.top);
+        (tx, ty)
+    }
+}
 
+/// The wall face under a screen pixel, as found by `Render::pick`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct WallPick {
+    pub x: usize,
+    pub y: usize,
+    pub direction: Direction,
+    pub u: f64,
+    pub v: f64,
+}
+
+/// How two frames differ, as found by `Render::compare`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ImageDiff {
+    /// The largest difference of any channel of any pixel.
+    pub max_delta: u8,
+    /// The mean of the squared channel differences, over every channel of
+    /// every pixel.
+    pub mse: f64,
+    /// The number of pixels with at least one differing channel.
+    pub differing: usize,
+}
+
+/// The vertical field of view matching `fov` for a `width`x`height` buffer
+/// with square pixels: `sin(vfov) = sin(fov) * height / width`. Both angles
+/// are measured from the view axis to the edge of the screen.
+fn aspect_vfov(fov: f64, width: u32, height: u32) -> f64 {
+    (fov.sin() * (height as f64) / (width as f64)).asin()
+}
+
+
+const DEBUG_SPACE: Rgb<u8> = Rgb([0, 0, 0]);
+const DEBUG_WALL: Rgb<u8> = Rgb([128, 128, 128]);
+const DEBUG_ITEM: Rgb<u8> = Rgb([255, 200, 0]);
+const DEBUG_CHECKER: [Rgb<u8>; 2] = [Rgb([40, 40, 40]), Rgb([200, 200, 200])];
+const DEBUG_FOG: Rgb<u8> = Rgb([64, 64, 96]);
+const DEBUG_WINDOW: Rgb<u8> = Rgb([96, 160, 192]);
+const DEBUG_DOOR: Rgb<u8> = Rgb([160, 96, 32]);
+const DEBUG_GRID: Rgb<u8> = Rgb([48, 48, 48]);
+const DEBUG_PLAYER: Rgb<u8> = Rgb([255, 0, 0]);
+const DEBUG_RAY: Rgb<u8> = Rgb([0, 255, 0]);
+const DEBUG_FOV: Rgb<u8> = Rgb([0, 96, 255]);
+const COMPASS_RING: Rgb<u8> = Rgb([255, 255, 255]);
+const COMPASS_NORTH: Rgb<u8> = Rgb([255, 0, 0]);
+
+/// Draws a straight line between two points given in pixel coordinates,
+/// ignoring the parts that fall outside the image.
+fn draw_line(img: &mut RgbImage, (x0, y0): (f64, f64), (x1, y1): (f64, f64), color: Rgb<u8>) {
+    let steps = (x1 - x0).abs().max((y1 - y0).abs()).ceil().max(1.0);
+
+    for i in 0..=(steps as usize) {
+        let t = i as f64 / steps;
+        let x = x0 + (x1 - x0) * t;
+        let y = y0 + (y1 - y0) * t;
+        if x >= 0.0 && y >= 0.0 && x < img.width() as f64 && y < img.height() as f64 {
+            img.put_pixel(x as u32, y as u32, color);
+        }
+    }
+}
+
+/// Samples `img` at fractional pixel coordinates, pixel centers being at
+/// integer coordinates, interpolating between the four nearest pixels.
+/// Points more than half a pixel outside the image have no color.
+fn sample_bilinear(img: &RgbImage, x: f64, y: f64) -> Option<Rgb<u8>> {
+    let (w, h) = (img.width() as f64, img.height() as f64);
+    if !(x >= -0.5 && y >= -0.5 && x < w - 0.5 && y < h - 0.5) {
+        return None;
+    }
+    let (x, y) = (x.clamp(0.0, w - 1.0), y.clamp(0.0, h - 1.0));
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(img.width() - 1), (y0 + 1).min(img.height() - 1));
+    let (fx, fy) = (x - x0 as f64, y - y0 as f64);
+    let top = blend(*img.get_pixel(x0, y0), *img.get_pixel(x1, y0), fx);
+    let bottom = blend(*img.get_pixel(x0, y1), *img.get_pixel(x1, y1), fx);
+    Some(blend(top, bottom, fy))
+}
+
+const BAYER: [[f64; 4]; 4] = [
+    [ 0.0,  8.0,  2.0, 10.0],
+    [12.0,  4.0, 14.0,  6.0],
+    [ 3.0, 11.0,  1.0,  9.0],
+    [15.0,  7.0, 13.0,  5.0],
+];
+
+fn nearest(palette: &[Rgb<u8>], [r, g, b]: [f64; 3]) -> Rgb<u8> {
+    let distance = |c: &&Rgb<u8>| {
+        let dr = c[0] as f64 - r;
+        let dg = c[1] as f64 - g;
+        let db = c[2] as f64 - b;
+        dr * dr + dg * dg + db * db
+    };
+
+    *palette.iter()
+        .min_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap())
+        .unwrap()
+}
+
+/// Chooses the mipmap level for a wall band `pixels` rows tall showing a
+/// texture `texels` rows tall, so that roughly one texel maps to one pixel.
+fn select_lod(texels: u32, pixels: u32) -> usize {
+    let ratio = texels as f64 / pixels as f64;
+    ratio.log2().max(0.0).floor() as usize
+}
+
+/// Whether a ray crossing into `cell` at `hit` stops there, and if so the
+/// face it sees. Doors open by a fraction `f` have slid sideways that far:
+/// rays pass through the opening, and see the slab's texture shifted by `f`.
+fn stop(cell: MapCell, hit: Hit) -> Option<Hit> {
+    match cell {
+        MapCell::Door(open) => {
+            let slid = open as f64 / DOOR_OPEN as f64;
+            if hit.position >= slid && open < DOOR_OPEN {
+                Some(Hit { position: hit.position - slid, ..hit })
+            } else {
+                None
+            }
+        }
+        cell if cell.is_opaque() => Some(hit),
+        _ => None,
+    }
+}
+
+/// The unclipped screen rows of the top and bottom of a wall at distance
+/// `perp_dist`, seen from `eye_height` above the floor with a vertical field
+/// of view of `vfov` radians.
+fn wall_extent(perp_dist: f64, eye_height: f64, vfov: f64, screen_height: u32) -> (f64, f64) {
+    let half_height = (screen_height as f64) / 2.0;
+    let vss = perp_dist * vfov.tan();
+    let top = half_height * (1.0 - (1.0 - eye_height) / vss);
+    let bottom = half_height * (1.0 + eye_height / vss);
+    (top, bottom)
+}
+
+/// The rows `(ceil, floor)` bounding a wall at distance `perp_dist` on a
+/// screen `screen_height` rows tall: the ceiling is drawn above `ceil`, the
+/// wall from `ceil` to `floor` and the floor from `floor` down.
+pub fn project_wall(perp_dist: f64, eye_height: f64, vfov: f64, screen_height: u32) -> (u32, u32) {
+    let (top, bottom) = wall_extent(perp_dist, eye_height, vfov, screen_height);
+    (clip(top, screen_height), clip(bottom, screen_height))
+}
+
+/// Rounds a fractional row down to a row index within `0..=bound`.
 fn clip(x: f64, bound: u32) -> u32 {
     if x < 0.0 {
         0
     } else if x >= (bound as f64) {
-        bound-1
+        bound
     } else {
         x.floor() as u32
     }
 }
 
+/// The color of walls facing `direction` with `Render::flat_shading`.
+pub fn flat_color(direction: Direction) -> Rgb<u8> {
+    match direction {
+        Direction::N => Rgb([200, 200, 200]),
+        Direction::S => Rgb([150, 150, 150]),
+        Direction::E => Rgb([100, 100, 100]),
+        Direction::W => Rgb([50, 50, 50]),
+    }
+}
+
+/// The color of the inside faces of walls, seen with `noclip`.
+pub const BACK_FACE: Rgb<u8> = Rgb([48, 0, 48]);
+
+/// Texels of this color are transparent in sprites without an alpha
+/// channel. Sprites with one are blended by their alpha instead.
+pub const SPRITE_KEY: Rgb<u8> = Rgb([255, 0, 255]);
+
+/// The closest distance at which walls are projected. Nearer walls are
+/// drawn as if at this distance, which keeps the projection finite when the
+/// camera touches a wall.
+const MIN_DISTANCE: f64 = 1e-4;
+
+/// The range `spawn_with_fov` and `set_fov` clamp fields of view to, in
+/// degrees. A null field of view would cast every column along the same ray.
+const FOV_RANGE: (f64, f64) = (0.1, 89.9);
+
+/// The range `set_vertical_scale` clamps wall height multipliers to.
+const VERTICAL_SCALE_RANGE: (f64, f64) = (0.1, 10.0);
+
+/// The field of view suggested by the map's `FOV` header, or 30 degrees.
+fn default_fov(map: &Map) -> f64 {
+    map.fov.unwrap_or(30.0)
+}
+
 impl Render {
 
+    /// Places the camera at the map's spawn point, with the field of view of
+    /// its `FOV` header if any.
     pub fn spawn(map: &Map) -> Self {
+        Self::spawn_with_fov(map, default_fov(map))
+    }
 
-        let fov = 30f64.to_radians();
+    /// Like `spawn`, with a horizontal field of view of `fov` degrees from
+    /// the view axis to the edge of the screen, clamped to `FOV_RANGE`.
+    pub fn spawn_with_fov(map: &Map, fov: f64) -> Self {
         let res = map.resolution;
+        Self::new(map, fov, res.0 as u32, res.1 as u32)
+    }
+
+    /// Like `spawn`, rendering into a `width`x`height` buffer instead of the
+    /// resolution given by the map's `R` header.
+    pub fn spawn_with_resolution(map: &Map, width: u32, height: u32) -> Self {
+        Self::new(map, default_fov(map), width, height)
+    }
+
+    fn new(map: &Map, fov: f64, width: u32, height: u32) -> Self {
+
+        let fov = fov.clamp(FOV_RANGE.0, FOV_RANGE.1).to_radians();
         let theta = map.spawn.direction.angle();
 
         Render { pos: v(map.spawn.x as f64 + 0.5,
                         map.spawn.y as f64 + 0.5)
                , cam: Vector::angle(theta)
+               , level: map.spawn.level
                , fov
-               , vfov: (fov.sin() * (res.1 as f64) / (res.0 as f64)).asin()
-               , buffer: ImageBuffer::new(res.0 as u32, res.1 as u32)
+               , vfov: aspect_vfov(fov, width, height)
+               , buffer: ImageBuffer::new(width, height)
                , height: 0.6
+               , aspect_correction: true
+               , fog: None
+               , debug_floor: false
+               , flat_shading: false
+               , noclip: false
+               , fisheye_correction: true
+               , angular_shading: 0.0
+               , tile_columns: 32
+               , linear_shading: false
+               , vertical_scale: 1.0
+               , roll: 0.0
+               , sky_pitch: 0.0
+               , time: 0.0
+               , depth_cue: 0.0
+               , near: 0.1
+               , far: 100.0
                }
     }
 
     pub fn render(&mut self, map: &Map) {
+        self.render_shaded(map, |_, _, pixel| pixel)
+    }
 
-        let grid_bounds = map.data.extents();
-        let grid = Grid { width: grid_bounds[1], height: grid_bounds[0] };
-        let screen_height = self.buffer.height();
-        let half_width: f64 = (self.buffer.width() as f64) / 2.0;
-        let half_height: f64 = (screen_height as f64) / 2.0;
-        let dx: Vector = self.cam.turn() * (self.fov.sin() / half_width);
+    /// Like `render`, passing every textured wall pixel through `shader`
+    /// along with the wall hit and texture coordinates. The shader gets the
+    /// color `render` would draw and returns the one to draw instead.
+    pub fn render_shaded<F>(&mut self, map: &Map, shader: F)
+        where F: Fn(&Hit, (f64, f64), Rgb<u8>) -> Rgb<u8>
+    {
+        let columns = self.render_walls(map, &shader);
+        let depths: Vec<_> = columns.iter().map(|column| column.depth).collect();
+        self.render_sprites(map, &depths);
+        self.apply_roll();
+    }
+
+    /// Draws the walls, ceiling and floor of every screen column through
+    /// `shader`, as described in `render_shaded`, returning the columns cast.
+    fn render_walls<F>(&mut self, map: &Map, shader: &F) -> Vec<Column>
+        where F: Fn(&Hit, (f64, f64), Rgb<u8>) -> Rgb<u8>
+    {
+        debug_assert!(self.buffer.width() <= 1 || self.fov.sin() > 0.0,
+                      "degenerate field of view {}", self.fov);
+
+        let (width, height) = self.buffer.dimensions();
+        let mut columns = Vec::with_capacity(width as usize);
+        let mut caster = self.caster(map);
+        let mut tile = Vec::with_capacity(self.tile_columns);
+
+        // Columns are shaded into a tile, then copied into the row-major
+        // buffer a row at a time.
+        for start in (0..width).step_by(self.tile_columns) {
+            let end = (start + self.tile_columns as u32).min(width);
+            tile.clear();
+            for x in start..end {
+                let column = self.cast_with(&mut caster, map, x);
+                tile.push(self.shade_with(map, &column, self.ray(x), shader));
+                columns.push(column);
+            }
+            for y in 0..height {
+                for (x, pixels) in (start..end).zip(&tile) {
+                    self.buffer.put_pixel(x, y, pixels[y as usize]);
+                }
+            }
+        }
+
+        columns
+    }
+
+    /// Fills the buffer with the background alone, the ceiling above the
+    /// horizon and the floor below it, without casting any rays. Walls and
+    /// sprites from another pass can then be drawn over it.
+    pub fn render_background(&mut self, map: &Map) {
+        let (width, height) = self.buffer.dimensions();
+        for x in 0..width {
+            let ray = self.ray(x);
+            for y in 0..height {
+                let pixel = self.background(map, ray, y);
+                self.buffer.put_pixel(x, y, pixel);
+            }
+        }
+    }
+
+    /// Like `render`, casting and shading the screen columns on `threads`
+    /// threads. Columns are computed independently and written back in
+    /// order, and sprites are drawn afterwards on the calling thread, so the
+    /// frame is byte for byte the one `render` draws, whatever the number of
+    /// threads: no step combines values in an order that depends on them.
+    pub fn render_parallel(&mut self, map: &Map, threads: usize) {
+        let width = self.buffer.width();
+        let chunk = (width as usize).div_ceil(threads.max(1)).max(1) as u32;
+        let this = &*self;
+
+        let columns: Vec<(f64, Vec<Rgb<u8>>)> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..width).step_by(chunk as usize)
+                .map(|start| scope.spawn(move || {
+                    let mut caster = this.caster(map);
+                    (start..(start + chunk).min(width))
+                        .map(|x| {
+                            let column = this.cast_with(&mut caster, map, x);
+                            (column.depth, this.shade(map, &column, this.ray(x)))
+                        })
+                        .collect::<Vec<_>>()
+                }))
+                .collect();
+            workers.into_iter().flat_map(|worker| worker.join().expect("render thread panicked")).collect()
+        });
+
+        let mut depths = Vec::with_capacity(width as usize);
+        for (x, (depth, pixels)) in columns.into_iter().enumerate() {
+            for (y, pixel) in pixels.into_iter().enumerate() {
+                self.buffer.put_pixel(x as u32, y as u32, pixel);
+            }
+            depths.push(depth);
+        }
+
+        self.render_sprites(map, &depths);
+        self.apply_roll();
+    }
+
+    /// Renders a `width`x`height` cylindrical panorama all around the camera,
+    /// starting from the view direction: column `x` looks `2π * x / width`
+    /// radians clockwise from `cam`, so the left and right edges join up.
+    /// Sprites and windows are not drawn.
+    pub fn render_panorama(&mut self, map: &Map, width: u32, height: u32) -> RgbImage {
+        let screen = std::mem::replace(&mut self.buffer, ImageBuffer::new(width, height));
+        // Rays all around the camera are projected by their own length.
+        let correction = std::mem::replace(&mut self.fisheye_correction, false);
+        let heading = self.cam.heading();
+        let mut caster = self.caster(map);
+
+        for x in 0..width {
+            let ray = Vector::angle(heading + std::f64::consts::TAU * x as f64 / width as f64);
+            let column = self.cast_ray(&mut caster, map, ray);
+            for (y, pixel) in self.shade(map, &column, ray).into_iter().enumerate() {
+                self.buffer.put_pixel(x, y as u32, pixel);
+            }
+        }
+
+        self.fisheye_correction = correction;
+        std::mem::replace(&mut self.buffer, screen)
+    }
+
+    /// Like `render`, also drawing the window cells in front of the walls,
+    /// blended front to back by the opacity of the window texture. Sprites
+    /// behind a window show through it.
+    pub fn render_transparent(&mut self, map: &Map) {
+        let window = match &map.window {
+            Some(window) => window,
+            None => return self.render(map),
+        };
+
+        let width = self.buffer.width();
+        let mut walls = Vec::with_capacity(width as usize);
+        let mut caster = self.caster(map);
+
+        for x in 0..width {
+            // The windows in front of the first opaque wall, nearest first.
+            let mut panes = Vec::new();
+            let wall = self.cast_through_windows(&mut caster, map, self.ray(x), Some(&mut panes));
+            for (y, pixel) in self.shade(map, &wall, self.ray(x)).into_iter().enumerate() {
+                self.buffer.put_pixel(x, y as u32, pixel);
+            }
+            walls.push((wall.depth, panes));
+        }
+
+        // Sprites behind the nearest window of their column are drawn
+        // first, to be seen through the glass, and the others over it.
+        let nearest = |panes: &[Column]| panes.first().map_or(0.0, |pane| pane.depth);
+        let behind: Vec<_> = walls.iter().map(|(wall, panes)| nearest(panes)..*wall).collect();
+        let before: Vec<_> = walls.iter().map(|(_, panes)| 0.0..nearest(panes)).collect();
+        self.render_sprites_within(map, &behind);
+
+        for (x, (_, panes)) in walls.iter().enumerate() {
+            let x = x as u32;
+            for y in 0..self.buffer.height() {
+                let mut color = [0.0; 3];
+                let mut opacity = 0.0;
+
+                for pane in panes.iter().filter(|pane| (pane.ceil..pane.floor).contains(&y)) {
+                    let texel = window.get_rgba(pane.uv(y));
+                    let a = (1.0 - opacity) * texel[3] as f64 / 255.0;
+                    for c in 0..3 {
+                        color[c] += a * texel[c] as f64;
+                    }
+                    opacity += a;
+                    if opacity >= 1.0 {
+                        break;
+                    }
+                }
+
+                let mut pixel = *self.buffer.get_pixel(x, y);
+                for c in 0..3 {
+                    pixel[c] = (color[c] + (1.0 - opacity) * pixel[c] as f64).round() as u8;
+                }
+                self.buffer.put_pixel(x, y, pixel);
+            }
+        }
+
+        self.render_sprites_within(map, &before);
+        self.apply_roll();
+    }
+
+    /// Draws the item sprites over the walls, farthest first so that nearer
+    /// sprites are drawn over farther ones. Sprite columns behind the wall
+    /// of that screen column, whose distance is given by `depths`, are
+    /// hidden.
+    fn render_sprites(&mut self, map: &Map, depths: &[f64]) {
+        let depths: Vec<_> = depths.iter().map(|&depth| 0.0..depth).collect();
+        self.render_sprites_within(map, &depths);
+    }
+
+    /// Like `render_sprites`, only drawing sprite columns whose distance
+    /// lies in the range `depths` gives for that screen column.
+    fn render_sprites_within(&mut self, map: &Map, depths: &[Range<f64>]) {
+        let data = self.cells(map);
+        let (h, w) = (data.extents()[0], data.extents()[1]);
+
+        let mut items: Vec<(Vector, u8)> = (0..h)
+            .flat_map(|y| (0..w).map(move |x| (x, y)))
+            .filter_map(|(x, y)| match data[[y, x]] {
+                MapCell::Item(id) => Some((v(x as f64 + 0.5, y as f64 + 0.5), id)),
+                _ => None,
+            })
+            .collect();
 
+        let pos = self.pos;
+        items.sort_by(|a, b| {
+            b.0.squared_distance(&pos).partial_cmp(&a.0.squared_distance(&pos)).unwrap()
+        });
 
-        for x in 0..self.buffer.width() {
+        for (center, id) in items {
+            self.draw_sprite(map.sprite_for(id), map.billboard_for(id), center, depths);
+        }
+    }
+
+    /// Draws a one cell wide, one wall high sprite standing on the floor at
+    /// `center`, in the plane given by `billboard`. Texels of the
+    /// `SPRITE_KEY` color are transparent.
+    fn draw_sprite(&mut self, tex: &Texture, billboard: Billboard, center: Vector, depths: &[Range<f64>]) {
+        match billboard {
+            Billboard::ViewFacing => self.draw_billboard(tex, center, depths),
+            Billboard::AxisX => self.draw_fixed_sprite(tex, v(1.0, 0.0), center, depths),
+            Billboard::AxisY => self.draw_fixed_sprite(tex, v(0.0, 1.0), center, depths),
+        }
+    }
+
+    /// Draws a sprite facing the camera, spanning the same width on screen
+    /// from every angle.
+    fn draw_billboard(&mut self, tex: &Texture, center: Vector, depths: &[Range<f64>]) {
+        let (sx, top) = match self.project_at(center, 1.0) {
+            Some(p) => p,
+            None => return,
+        };
+        let (_, bottom) = self.project_at(center, 0.0).unwrap();
 
-            let ray: Vector = self.cam + (dx * (x as f64 - half_width));
+        let forward = (center - self.pos).dot(self.cam) / self.cam.squared_norm();
+        let half_width = (self.buffer.width() as f64) / 2.0;
+        let half_size = 0.5 * half_width / (self.fov.sin() * forward);
+        let (left, right) = (sx - half_size, sx + half_size);
+        let distance = self.view_distance(center - self.pos);
+
+        let w = self.buffer.width();
+        for x in clip(left, w)..clip(right, w) {
+            if !depths[x as usize].contains(&distance) {
+                continue;
+            }
+            self.draw_sprite_column(tex, x, (x as f64 - left) / (right - left), (top, bottom));
+        }
+    }
 
-            let hit = Raycaster::new(self.pos, ray, grid)
-                .filter(|h| map.data[[h.y, h.x]] == MapCell::Wall)
-                .next().expect("Oh no! the impossible happened, no ray hits!");
-            
-            let vss = hit.distance.sqrt() * self.vfov.tan();
+    /// Draws a sprite lying in the vertical plane through `center` along
+    /// `axis`, intersecting each screen column's ray with it so that it is
+    /// foreshortened like a wall.
+    fn draw_fixed_sprite(&mut self, tex: &Texture, axis: Vector, center: Vector, depths: &[Range<f64>]) {
+        let cross = |a: Vector, b: Vector| a.x() * b.y() - a.y() * b.x();
+        let start = center - axis * 0.5;
+        let rel = start - self.pos;
 
-            let ceil: u32 = clip(half_height * (1.0 - (1.0 - self.height) / vss), screen_height);
-            let floor: u32 = clip(half_height * (1.0 + self.height / vss), screen_height);
+        let w = self.buffer.width();
+        let columns = match (self.project(start), self.project(start + axis)) {
+            (Some((a, _)), Some((b, _))) => clip(a.min(b), w)..(clip(a.max(b), w) + 1).min(w),
+            _ => 0..w,
+        };
 
-            for y in 0..ceil {
-                self.buffer.put_pixel(x, y, map.ceiling);
+        for x in columns {
+            let ray = self.ray(x);
+            let denom = cross(ray, axis);
+            if denom == 0.0 {
+                continue;
+            }
+            let (along, tu) = (cross(rel, axis) / denom, cross(rel, ray) / denom);
+            if along <= 0.0 || !(0.0..=1.0).contains(&tu) {
+                continue;
+            }
+            let point = self.pos + ray * along;
+            if !depths[x as usize].contains(&self.view_distance(point - self.pos)) {
+                continue;
             }
-            
-            let tex = map.texture(hit.direction);
-            let tdy = 1.0 / ((floor - ceil) as f64);
+            if let (Some((_, top)), Some((_, bottom))) = (self.project_at(point, 1.0), self.project_at(point, 0.0)) {
+                self.draw_sprite_column(tex, x, tu, (top, bottom));
+            }
+        }
+    }
 
-            for y in ceil..floor {
-                let ty = (y - ceil) as f64 * tdy;
-                let tx = match hit.direction {
-                    Direction::S | Direction::W => hit.position,
-                    Direction::N | Direction::E => 1.0 - hit.position
+    /// Draws texture column `tu` of a sprite over screen column `x`, between
+    /// the fractional rows `top` and `bottom`.
+    fn draw_sprite_column(&mut self, tex: &Texture, x: u32, tu: f64, (top, bottom): (f64, f64)) {
+        let h = self.buffer.height();
+        let alpha = tex.has_alpha();
+        for y in clip(top, h)..clip(bottom, h) {
+            let tv = (y as f64 - top) / (bottom - top);
+            if alpha {
+                let Rgba([r, g, b, a]) = tex.get_rgba_at((tu, tv), self.time);
+                let pixel = match a {
+                    0 => continue,
+                    255 => Rgb([r, g, b]),
+                    a => blend(*self.buffer.get_pixel(x, y), Rgb([r, g, b]), a as f64 / 255.0),
                 };
-                let pixel = tex.get((tx, ty));
                 self.buffer.put_pixel(x, y, pixel);
+            } else {
+                let pixel = tex.get_at((tu, tv), self.time);
+                if pixel != SPRITE_KEY {
+                    self.buffer.put_pixel(x, y, pixel);
+                }
+            }
+        }
+    }
+
+    /// Renders the frame, and a depth image giving for every wall pixel the
+    /// distance to the wall in units of `1 / DEPTH_SCALE` cells. Ceiling
+    /// and floor pixels, and walls too far to encode, are `DEPTH_NONE`.
+    pub fn render_with_depth(&mut self, map: &Map) -> (RgbImage, DepthImage) {
+        let columns = self.render_walls(map, &|_, _, pixel| pixel);
+
+        let mut depth = ImageBuffer::from_pixel(self.buffer.width(), self.buffer.height(), Luma([DEPTH_NONE]));
+        for (x, column) in columns.iter().enumerate() {
+            let value = (column.depth * DEPTH_SCALE).round().min((DEPTH_NONE - 1) as f64) as u16;
+            for y in column.ceil..column.floor {
+                depth.put_pixel(x as u32, y, Luma([value]));
+            }
+        }
+
+        let depths: Vec<_> = columns.iter().map(|column| column.depth).collect();
+        self.render_sprites(map, &depths);
+        self.apply_roll();
+
+        (self.buffer.clone(), self.roll_depth(depth))
+    }
+
+    /// Lazily renders the frame one column at a time, yielding each column
+    /// index with its pixels from top to bottom. Columns are those of the
+    /// upright frame, before any camera roll.
+    pub fn columns<'a>(&'a self, map: &'a Map) -> impl Iterator<Item = (u32, Vec<Rgb<u8>>)> + 'a {
+        (0..self.buffer.width()).map(move |x| (x, self.column(map, x)))
+    }
+
+    /// The `(x, y)` cells of the walls hit first by some screen column, that
+    /// is the walls a render would draw, found without drawing them.
+    pub fn visible_cells(&self, map: &Map) -> HashSet<(usize, usize)> {
+        let mut caster = self.caster(map);
+        (0..self.buffer.width())
+            .map(|x| self.cast_with(&mut caster, map, x))
+            .filter(|column| column.depth.is_finite())
+            .map(|column| (column.hit.x, column.hit.y))
+            .collect()
+    }
+
+    /// The grid of the level being rendered.
+    fn cells<'a>(&self, map: &'a Map) -> &'a Array2D<MapCell> {
+        map.level(self.level).expect("rendering a missing level")
+    }
+
+    /// The direction of the ray cast for screen column `x`.
+    fn ray(&self, x: u32) -> Vector {
+        let half_width: f64 = (self.buffer.width() as f64) / 2.0;
+        let dx: Vector = self.cam.turn() * (self.fov.sin() / half_width);
+        self.cam + (dx * (x as f64 - half_width))
+    }
+
+    /// Casts the ray for screen column `x` and projects the first wall it
+    /// hits onto the screen.
+    fn cast(&self, map: &Map, x: u32) -> Column {
+        let mut caster = self.caster(map);
+        self.cast_with(&mut caster, map, x)
+    }
+
+    /// A raycaster over the rendered level, wrapping around the edges of
+    /// toroidal maps. `cast_with` aims it at a column before use.
+    fn caster(&self, map: &Map) -> Raycaster {
+        let caster = Raycaster::new(self.pos, self.cam, grid_of(self.cells(map)));
+        if map.wrap { caster.wrapping() } else { caster }
+    }
+
+    /// Like `cast`, restarting `caster` on the ray rather than building one.
+    fn cast_with(&self, caster: &mut Raycaster, map: &Map, x: u32) -> Column {
+        self.cast_ray(caster, map, self.ray(x))
+    }
+
+    /// Casts `ray` from the camera and projects the first wall it hits.
+    fn cast_ray(&self, caster: &mut Raycaster, map: &Map, ray: Vector) -> Column {
+        self.cast_through_windows(caster, map, ray, None)
+    }
+
+    /// Like `cast_ray`. Given `panes`, the ray goes through window cells
+    /// instead of stopping there, and the windows it crosses in front of
+    /// the wall are projected onto `panes`, nearest first.
+    fn cast_through_windows(&self, caster: &mut Raycaster, map: &Map, ray: Vector,
+                            mut panes: Option<&mut Vec<Column>>) -> Column {
+        let data = self.cells(map);
+        caster.reset(self.pos, ray, grid_of(data));
+
+        if self.noclip {
+            if let Some((cx, cy)) = self.wall_cell(data) {
+                // The first crossing leaves the camera's cell, through the
+                // opposite face of the neighbour it enters. Rays leaving the
+                // map altogether see the inside of the cell right away.
+                let hit = caster.next().map_or(
+                    Hit { x: cx, y: cy, direction: Direction::N, position: 0.0, squared_distance: 0.0 },
+                    |hit| Hit { x: cx, y: cy, direction: hit.direction.opposite(), ..hit });
+                return Column { back_face: true, ..self.project_hit(hit, ray) };
+            }
+        }
+
+        let hit = caster.find_map(|h| match (data[[h.y, h.x]], panes.as_mut()) {
+            (MapCell::Window, Some(panes)) => {
+                panes.push(self.project_cell(map, h, ray));
+                None
             }
+            (cell, _) => stop(cell, h),
+        });
+        match hit {
+            Some(hit) => self.project_cell(map, hit, ray),
+            None => self.open_column(),
+        }
+    }
+
+    /// Projects the wall face of `hit`, found along `ray`, at the height of
+    /// its cell.
+    fn project_cell(&self, map: &Map, hit: Hit, ray: Vector) -> Column {
+        match map.wall_height(self.cells(map)[[hit.y, hit.x]]) {
+            height if height < 1.0 => self.project_hit(hit, ray).with_height(height),
+            _ => self.project_hit(hit, ray),
+        }
+    }
+
+    /// The column of a ray that never hits a wall, such as one looking down
+    /// an open corridor of a toroidal map: a wall infinitely far away,
+    /// leaving the ceiling and floor meeting at the horizon.
+    fn open_column(&self) -> Column {
+        let horizon = self.horizon_row();
+        let hit = Hit { x: 0, y: 0, direction: Direction::N, position: 0.0, squared_distance: f64::INFINITY };
+        Column {
+            hit,
+            depth: f64::INFINITY,
+            top: horizon as f64,
+            bottom: horizon as f64,
+            ceil: horizon,
+            floor: horizon,
+            back_face: false,
+        }
+    }
+
+    /// The distance at which a point `rel` away from the camera is
+    /// projected: the Euclidean distance, or with fisheye correction the
+    /// distance along the view axis.
+    fn view_distance(&self, rel: Vector) -> f64 {
+        if self.fisheye_correction {
+            rel.dot(self.cam) / self.cam.squared_norm().sqrt()
+        } else {
+            rel.squared_norm().sqrt()
+        }
+    }
+
+    /// The opaque cell the camera stands in, if any.
+    fn wall_cell(&self, data: &Array2D<MapCell>) -> Option<(usize, usize)> {
+        let (x, y) = (self.pos.x().floor(), self.pos.y().floor());
+        let grid = grid_of(data);
+        if x < 0.0 || y < 0.0 || x as usize >= grid.width || y as usize >= grid.height {
+            return None;
+        }
+        let (x, y) = (x as usize, y as usize);
+        if data[[y, x]].is_opaque() { Some((x, y)) } else { None }
+    }
+
+    /// Projects the wall face of `hit`, found along `ray`, onto the screen.
+    fn project_hit(&self, hit: Hit, ray: Vector) -> Column {
+        let screen_height = self.buffer.height();
+        let depth = (self.view_distance(ray) * hit.distance() / ray.squared_norm().sqrt()).max(MIN_DISTANCE);
+        let (top, bottom) = wall_extent(depth, self.height, self.projection_vfov(), screen_height);
+        let (ceil, floor) = project_wall(depth, self.height, self.projection_vfov(), screen_height);
 
-            for y in floor..self.buffer.height() {
-                self.buffer.put_pixel(x, y, map.floor);
+        Column { hit, depth, top, bottom, ceil, floor, back_face: false }
+    }
+
+    /// Renders the walls, ceiling and floor of screen column `x`, from top
+    /// to bottom.
+    fn column(&self, map: &Map, x: u32) -> Vec<Rgb<u8>> {
+        self.shade(map, &self.cast(map, x), self.ray(x))
+    }
+
+    /// Renders the pixels of the screen column cast along `ray`, showing the
+    /// wall of `column`.
+    fn shade(&self, map: &Map, column: &Column, ray: Vector) -> Vec<Rgb<u8>> {
+        self.shade_with(map, column, ray, &|_, _, pixel| pixel)
+    }
+
+    /// Like `shade`, passing the wall pixels through `shader` as described
+    /// in `render_shaded`.
+    fn shade_with<F>(&self, map: &Map, column: &Column, ray: Vector, shader: &F) -> Vec<Rgb<u8>>
+        where F: Fn(&Hit, (f64, f64), Rgb<u8>) -> Rgb<u8>
+    {
+
+        let screen_height = self.buffer.height();
+        let mut pixels = Vec::with_capacity(screen_height as usize);
+
+        let Column { hit, ceil, floor, .. } = *column;
+
+        for y in 0..ceil {
+            pixels.push(self.background(map, ray, y));
+        }
+
+        if column.back_face {
+            pixels.extend((ceil..floor).map(|_| BACK_FACE));
+            pixels.extend((floor..screen_height).map(|y| self.background(map, ray, y)));
+            return pixels;
+        }
+
+        let cell = self.cells(map)[[hit.y, hit.x]];
+        // Flat shading samples no texture, so lazy ones stay undecoded.
+        let textured = if self.flat_shading {
+            None
+        } else {
+            let tex = match (cell, &map.door) {
+                (MapCell::Door(_), Some(door)) => door,
+                _ => map.texture(hit.direction),
+            };
+            // Short walls keep the texture scale of a full wall.
+            Some((tex, select_lod(tex.height(), floor - clip(column.top, floor))))
+        };
+        let tint = match cell {
+            MapCell::TaggedWall(id) => map.tints.get(&id),
+            _ => None,
+        };
+        let emissive = matches!(cell, MapCell::TaggedWall(id) if map.emissive.contains(&id));
+        let facing = (ray.dot(hit.direction.normal()) / ray.squared_norm().sqrt()).abs();
+        let angular = 1.0 - self.angular_shading * (1.0 - facing);
+
+        for y in ceil..floor {
+            let (u, v) = column.uv(y);
+            let mut pixel = match textured {
+                None => flat_color(hit.direction),
+                Some((tex, lod)) => tex.get_lod_at((u, v + tex.voffset()), lod, self.time),
+            };
+            if self.linear_shading {
+                let mut light = gamma().linear(pixel);
+                if let Some(tint) = tint {
+                    let tint = gamma().linear(*tint);
+                    light = [0, 1, 2].map(|c| light[c] * tint[c]);
+                }
+                if !emissive && self.angular_shading > 0.0 {
+                    light = light.map(|l| l * angular);
+                }
+                pixel = gamma().srgb(light);
+            } else {
+                if let Some(tint) = tint {
+                    for c in 0..3 {
+                        pixel[c] = (pixel[c] as u16 * tint[c] as u16 / 255) as u8;
+                    }
+                }
+                if !emissive && self.angular_shading > 0.0 {
+                    pixel = Rgb(pixel.0.map(|c| (c as f64 * angular).round() as u8));
+                }
+            }
+            if !emissive {
+                if self.depth_cue > 0.0 {
+                    pixel = depth_cue(pixel, column.depth, self.depth_cue);
+                }
+                match &self.fog {
+                    Some(fog) if self.linear_shading => pixel = fog.apply_linear(pixel, column.depth),
+                    Some(fog) => pixel = fog.apply(pixel, column.depth),
+                    None => (),
+                }
             }
+            pixels.push(shader(&hit, (u, v), pixel));
+        }
+
+        for y in floor..screen_height {
+            pixels.push(self.background(map, ray, y));
+        }
+
+        pixels
+    }
+
+    /// The color `render` gives pixel `(px, py)` when no wall covers it: the
+    /// ceiling color or gradient above the horizon, and the floor color or
+    /// gradient, or the debug checkerboard, below. Fog only applies to walls.
+    pub fn shade_background(&self, map: &Map, px: u32, py: u32) -> Rgb<u8> {
+        self.background(map, self.ray(px), py)
+    }
 
+    /// Like `shade_background`, for the screen column cast along `ray`.
+    fn background(&self, map: &Map, ray: Vector, py: u32) -> Rgb<u8> {
+        let y = py as f64 + 0.5;
+        let half_height = self.buffer.height() as f64 / 2.0;
+        if y <= half_height {
+            return match map.ceiling_gradient {
+                Some((top, horizon)) => blend(top, horizon, self.sky_v(y)),
+                None => map.ceiling,
+            };
+        }
+        if !self.debug_floor {
+            return match map.floor_gradient {
+                Some((near, far)) => blend(near, far, (2.0 * half_height - y) / half_height),
+                None => map.floor,
+            };
         }
 
+        let p = self.pos + ray * (self.floor_distance(y) / self.view_distance(ray));
+        let parity = (p.x().floor() + p.y().floor()).rem_euclid(2.0);
+        DEBUG_CHECKER[parity as usize]
     }
 
-}
\ No newline at end of file
+    /// Where the `CG` gradient is sampled at fractional row `y` above the
+    /// horizon, from 0 for its top color to 1 for its horizon color. The sky
+    /// pitch moves the gradient down by as many rows as the horizon would
+    /// move for a camera pitched that far.
+    fn sky_v(&self, y: f64) -> f64 {
+        let half_height = self.buffer.height() as f64 / 2.0;
+        let shift = half_height * self.sky_pitch.tan() / self.projection_vfov().tan();
+        ((y - shift) / half_height).clamp(0.0, 1.0)
+    }
+
+    /// The distance from the camera at which the floor appears at the
+    /// center of screen row `screen_y`, as drawn by the debug floor: along
+    /// the view axis with fisheye correction, along the column's ray
+    /// without. Rows at or above the horizon are infinitely far.
+    pub fn floor_row_distance(&self, screen_y: u32) -> f64 {
+        self.floor_distance(screen_y as f64 + 0.5)
+    }
+
+    /// The distance along a ray at which the floor appears at screen row
+    /// `y` (measured in fractional rows), inverting the wall projection in
+    /// `cast`. Rows at or above the horizon are infinitely far.
+    fn floor_distance(&self, y: f64) -> f64 {
+        let half_height = (self.buffer.height() as f64) / 2.0;
+        if y <= half_height {
+            return f64::INFINITY;
+        }
+        self.height * half_height / ((y - half_height) * self.projection_vfov().tan())
+    }
+
+    /// Projects a world point at eye height onto the screen, returning its
+    /// fractional `(x, y)` pixel coordinates in the upright frame, before
+    /// any camera roll, or `None` if it is behind the camera.
+    pub fn project(&self, world: Vector) -> Option<(f64, f64)> {
+        self.project_at(world, self.height)
+    }
+
+    /// Projects a world point at height `z` above the floor (in wall
+    /// heights) onto the screen, consistently with the wall projection.
+    pub fn project_at(&self, world: Vector, z: f64) -> Option<(f64, f64)> {
+        let rel = world - self.pos;
+        let norm = self.cam.squared_norm();
+        let forward = rel.dot(self.cam) / norm;
+        let side = rel.dot(self.cam.turn()) / norm;
+
+        if forward <= 0.0 {
+            return None;
+        }
+
+        let half_width = (self.buffer.width() as f64) / 2.0;
+        let half_height = (self.buffer.height() as f64) / 2.0;
+
+        let x = half_width + (side / forward) * half_width / self.fov.sin();
+        let vss = self.view_distance(rel) * self.projection_vfov().tan();
+        let y = half_height * (1.0 + (self.height - z) / vss);
+
+        Some((x, y))
+    }
+
+    /// Finds the wall shown at screen pixel `(px, py)`, and the texture
+    /// coordinates of that pixel within the wall face. Returns `None` for
+    /// ceiling and floor pixels. Pixels are those of the upright frame,
+    /// before any camera roll.
+    pub fn pick(&self, map: &Map, px: u32, py: u32) -> Option<WallPick> {
+        if px >= self.buffer.width() {
+            return None;
+        }
+
+        let column = self.cast(map, px);
+        if py < column.ceil || py >= column.floor {
+            return None;
+        }
+
+        let (u, v) = column.uv(py);
+        let Hit { x, y, direction, .. } = column.hit;
+
+        Some(WallPick { x, y, direction, u, v })
+    }
+
+    /// The horizontal field of view, in degrees.
+    pub fn fov(&self) -> f64 {
+        self.fov.to_degrees()
+    }
+
+    /// The vertical field of view, in degrees.
+    pub fn vfov(&self) -> f64 {
+        self.vfov.to_degrees()
+    }
+
+    /// The height of the camera above the floor, in wall heights.
+    pub fn eye_height(&self) -> f64 {
+        self.height
+    }
+
+    /// When enabled (the default), the vertical field of view is derived from
+    /// the horizontal one and the buffer's aspect ratio, as per `aspect_vfov`.
+    pub fn set_aspect_correction(&mut self, enabled: bool) {
+        self.aspect_correction = enabled;
+        if enabled {
+            self.vfov = aspect_vfov(self.fov, self.buffer.width(), self.buffer.height());
+        }
+    }
+
+    /// Sets the horizontal field of view, in degrees from the view axis to
+    /// the edge of the screen, clamped to `FOV_RANGE`.
+    pub fn set_fov(&mut self, degrees: f64) {
+        self.fov = degrees.clamp(FOV_RANGE.0, FOV_RANGE.1).to_radians();
+        if self.aspect_correction {
+            self.vfov = aspect_vfov(self.fov, self.buffer.width(), self.buffer.height());
+        }
+    }
+
+    pub fn set_eye_height(&mut self, height: f64) {
+        self.height = height;
+    }
+
+    /// Enables distance fog on walls, or disables it with `None`.
+    pub fn set_fog(&mut self, fog: Option<Fog>) {
+        self.fog = fog;
+    }
+
+    /// Sets the distances that `normalized_depth` maps to 0 and 1.
+    pub fn set_depth_range(&mut self, near: f64, far: f64) {
+        self.near = near;
+        self.far = far;
+    }
+
+    /// Maps a distance between the near and far planes into `[0, 1]`, with
+    /// the usual perspective `(1/near - 1/d) / (1/near - 1/far)` so that
+    /// precision is spent close to the camera. Distances outside the planes
+    /// are clamped.
+    pub fn normalized_depth(&self, perp_dist: f64) -> f64 {
+        let d = perp_dist.clamp(self.near, self.far);
+        (1.0 / self.near - 1.0 / d) / (1.0 / self.near - 1.0 / self.far)
+    }
+
+    /// Projects walls and sprites by their distance along the view axis
+    /// rather than their Euclidean distance, so that flat walls look flat
+    /// instead of bulging at the center of the screen. On by default.
+    pub fn set_fisheye_correction(&mut self, on: bool) {
+        self.fisheye_correction = on;
+    }
+
+    pub fn fisheye_correction(&self) -> bool {
+        self.fisheye_correction
+    }
+
+
+    /// Sets how many screen columns `render` shades before copying them
+    /// into the buffer, which is stored by rows. This only affects speed;
+    /// a value of 1 copies every column as soon as it is shaded.
+    pub fn set_tile_columns(&mut self, columns: usize) {
+        self.tile_columns = columns.max(1);
+    }
+
+    /// Applies tints, angular shading and fog to walls in linear light
+    /// rather than on sRGB values, which keeps fogged midtones from
+    /// darkening. Depth cueing is unaffected.
+    pub fn set_linear_shading(&mut self, on: bool) {
+        if on {
+            gamma();
+        }
+        self.linear_shading = on;
+    }
+
+    /// Darkens walls seen at a grazing angle, multiplying them by
+    /// `1 - strength * (1 - |cos θ|)` where `θ` is the angle between the ray
+    /// and the wall normal. A `strength` of 0 disables it.
+    pub fn set_angular_shading(&mut self, strength: f64) {
+        self.angular_shading = strength;
+    }
+
+    /// Darkens and desaturates walls with distance, which cues depth while
+    /// keeping their hue, unlike fog. A `strength` of 0 disables it.
+    pub fn set_depth_cue(&mut self, strength: f64) {
+        self.depth_cue = strength;
+    }
+
+    /// Rolls the camera by `radians` clockwise around the view axis. The
+    /// frame is rendered upright, then rotated around its center, the
+    /// corners left uncovered filled with the fog color, or black. The depth
+    /// image of `render_with_depth` is rotated alike, while `pick`,
+    /// `project`, `project_at` and `columns` describe the upright frame.
+    pub fn set_roll(&mut self, radians: f64) {
+        self.roll = radians;
+    }
+
+    /// Samples the `CG` ceiling gradient as if the camera were pitched up by
+    /// `radians`, so that a positive pitch shows more of its top color
+    /// rather than the same band. Walls, the floor and the horizon are not
+    /// moved, as the projection itself has no pitch.
+    pub fn set_sky_pitch(&mut self, radians: f64) {
+        self.sky_pitch = radians;
+    }
+
+    /// Rotates the finished frame by the camera roll.
+    fn apply_roll(&mut self) {
+        if self.roll == 0.0 {
+            return;
+        }
+        let clear = self.fog.as_ref().map_or(Rgb([0, 0, 0]), |fog| fog.color);
+        let (w, h) = self.buffer.dimensions();
+
+        let upright = &self.buffer;
+        let rolled = ImageBuffer::from_fn(w, h, |x, y| {
+            let (sx, sy) = self.unroll(x, y);
+            sample_bilinear(upright, sx, sy).unwrap_or(clear)
+        });
+        self.buffer = rolled;
+    }
+
+    /// Rotates a depth image of the upright frame by the camera roll, like
+    /// `apply_roll` but taking the nearest pixel rather than blending depths.
+    fn roll_depth(&self, depth: DepthImage) -> DepthImage {
+        if self.roll == 0.0 {
+            return depth;
+        }
+        let (w, h) = depth.dimensions();
+        ImageBuffer::from_fn(w, h, |x, y| {
+            let (sx, sy) = self.unroll(x, y);
+            let (sx, sy) = (sx.round(), sy.round());
+            if sx >= 0.0 && sy >= 0.0 && sx < w as f64 && sy < h as f64 {
+                *depth.get_pixel(sx as u32, sy as u32)
+            } else {
+                Luma([DEPTH_NONE])
+            }
+        })
+    }
+
+    /// The point of the upright frame shown at pixel `(x, y)` of the rolled
+    /// one, in fractional pixel coordinates with pixel centers at integer
+    /// coordinates.
+    fn unroll(&self, x: u32, y: u32) -> (f64, f64) {
+        let (w, h) = self.buffer.dimensions();
+        let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+        let (sin, cos) = self.roll.sin_cos();
+        let (dx, dy) = (x as f64 + 0.5 - cx, y as f64 + 0.5 - cy);
+        (cx + dx * cos + dy * sin - 0.5, cy - dx * sin + dy * cos - 0.5)
+    }
+
+    /// Multiplies the projected height of walls, sprites and the floor by
+    /// `scale`, within `VERTICAL_SCALE_RANGE`, regardless of the vertical
+    /// field of view: above 1 walls look taller, below 1 squashed.
+    pub fn set_vertical_scale(&mut self, scale: f64) {
+        self.vertical_scale = scale.clamp(VERTICAL_SCALE_RANGE.0, VERTICAL_SCALE_RANGE.1);
+    }
+
+    /// The vertical field of view things are projected with: `vfov`,
+    /// narrowed or widened by the vertical scale.
+    fn projection_vfov(&self) -> f64 {
+        (self.vfov.tan() / self.vertical_scale).atan()
+    }
+
+    /// Sets the vertical field of view, in degrees from the view axis to the
+    /// top of the screen, independently of the horizontal one. This turns
+    /// aspect correction off.
+    pub fn set_vfov(&mut self, degrees: f64) {
+        self.aspect_correction = false;
+        self.vfov = degrees.to_radians();
+    }
+
+    /// The animation clock, read by every time-dependent effect.
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+
+    /// Moves the animation clock forward by `dt`.
+    pub fn advance(&mut self, dt: f64) {
+        self.time += dt;
+    }
+
+    /// Rotates the camera by `delta` radians. The direction is rebuilt from
+    /// its angle, kept in `[0, 2π)`, so it stays a unit vector however many
+    /// times it is turned.
+    pub fn turn(&mut self, delta: f64) {
+        let theta = (self.cam.heading() + delta).rem_euclid(2.0 * std::f64::consts::PI);
+        self.cam = Vector::angle(theta);
+    }
+
+    /// Renders one frame per `(pos, cam)` pose, each into a fresh buffer.
+    /// The renderer is left at the last pose with a blank buffer.
+    pub fn render_poses(&mut self, map: &Map, poses: &[(Vector, Vector)]) -> Vec<RgbImage> {
+        let (w, h) = self.buffer.dimensions();
+
+        poses.iter().map(|&(pos, cam)| {
+            self.pos = pos;
+            self.cam = cam;
+            self.render(map);
+            std::mem::replace(&mut self.buffer, ImageBuffer::new(w, h))
+        }).collect()
+    }
+
+    /// Renders the view from the pose `(pos, cam)` into `dest`, whatever its
+    /// size, such as a rear-view mirror drawn over the main view. Under
+    /// aspect correction, the vertical field of view follows the shape of
+    /// `dest`. The camera and buffer of `self` are left as they were.
+    pub fn render_viewport(&mut self, map: &Map, (pos, cam): (Vector, Vector), dest: &mut RgbImage) {
+        let (w, h) = dest.dimensions();
+        let viewport = std::mem::replace(dest, ImageBuffer::new(0, 0));
+        let screen = std::mem::replace(&mut self.buffer, viewport);
+        let saved = (self.pos, self.cam, self.vfov);
+
+        self.pos = pos;
+        self.cam = cam;
+        if self.aspect_correction {
+            self.vfov = aspect_vfov(self.fov, w, h);
+        }
+        self.render(map);
+
+        *dest = std::mem::replace(&mut self.buffer, screen);
+        (self.pos, self.cam, self.vfov) = saved;
+    }
+
+    /// Draws a compass of `radius` pixels around `center` over the frame,
+    /// with an `N` marker in the direction of world north as seen by the
+    /// camera: at the top when facing north, on the left when facing east.
+    pub fn draw_compass(&mut self, center: (u32, u32), radius: u32) {
+        let (cx, cy) = (center.0 as f64, center.1 as f64);
+        let radius = radius as f64;
+
+        let segments = 32;
+        for i in 0..segments {
+            let point = |i: usize| {
+                let a = Vector::angle(std::f64::consts::TAU * i as f64 / segments as f64) * radius;
+                (cx + a.x(), cy + a.y())
+            };
+            draw_line(&mut self.buffer, point(i), point(i + 1), COMPASS_RING);
+        }
+
+        let unit = |d: Vector| d * (1.0 / d.squared_norm().sqrt());
+        let (forward, right) = (unit(self.cam), unit(self.cam.turn()));
+        let north = Direction::N.normal();
+        let (mx, my) = (cx + north.dot(right) * radius * 0.6, cy - north.dot(forward) * radius * 0.6);
+
+        let size = (radius * 0.25).max(1.0);
+        let (left, top, rightmost, bottom) = (mx - size, my - size, mx + size, my + size);
+        draw_line(&mut self.buffer, (left, bottom), (left, top), COMPASS_NORTH);
+        draw_line(&mut self.buffer, (left, top), (rightmost, bottom), COMPASS_NORTH);
+        draw_line(&mut self.buffer, (rightmost, bottom), (rightmost, top), COMPASS_NORTH);
+    }
+
+    /// Draws a top-down view of the whole map, `zoom` pixels per cell, with
+    /// the cell grid, the camera position, the edges of the field of view
+    /// and the central ray, each traced to the first wall it hits.
+    pub fn debug_topdown(&self, map: &Map, zoom: f64) -> RgbImage {
+        let data = self.cells(map);
+        let grid = grid_of(data);
+        let width = (grid.width as f64 * zoom) as u32;
+        let height = (grid.height as f64 * zoom) as u32;
+
+        let mut img = ImageBuffer::from_fn(width, height, |px, py| {
+            let x = ((px as f64 / zoom) as usize).min(grid.width - 1);
+            let y = ((py as f64 / zoom) as usize).min(grid.height - 1);
+            match data[[y, x]] {
+                MapCell::Space => DEBUG_SPACE,
+                MapCell::Wall | MapCell::TaggedWall(_) => DEBUG_WALL,
+                MapCell::Item(_) => DEBUG_ITEM,
+                MapCell::Fog => DEBUG_FOG,
+                MapCell::Window => DEBUG_WINDOW,
+                MapCell::Door(_) => DEBUG_DOOR,
+            }
+        });
+
+        for x in 0..=grid.width {
+            let px = x as f64 * zoom;
+            draw_line(&mut img, (px, 0.0), (px, height as f64), DEBUG_GRID);
+        }
+
+        for y in 0..=grid.height {
+            let py = y as f64 * zoom;
+            draw_line(&mut img, (0.0, py), (width as f64, py), DEBUG_GRID);
+        }
+
+        let to_screen = |p: Vector| (p.x() * zoom, p.y() * zoom);
+        let edge = self.cam.turn() * self.fov.sin();
+
+        let rays = [
+            (self.cam + edge, DEBUG_FOV),
+            (self.cam + edge * -1.0, DEBUG_FOV),
+            (self.cam, DEBUG_RAY),
+        ];
+
+        for &(ray, color) in &rays {
+            if let Some(hit) = Raycaster::new(self.pos, ray, grid).find_map(|h| stop(data[[h.y, h.x]], h)) {
+                let end = self.pos + ray * (hit.squared_distance / ray.squared_norm()).sqrt();
+                draw_line(&mut img, to_screen(self.pos), to_screen(end), color);
+            }
+        }
+
+        let (cx, cy) = to_screen(self.pos);
+        let radius = (zoom / 4.0).max(1.0);
+        for py in (cy - radius).floor() as i64 ..= (cy + radius).ceil() as i64 {
+            for px in (cx - radius).floor() as i64 ..= (cx + radius).ceil() as i64 {
+                let (dx, dy) = (px as f64 - cx, py as f64 - cy);
+                if px >= 0 && py >= 0 && (px as u32) < width && (py as u32) < height
+                    && dx * dx + dy * dy <= radius * radius {
+                    img.put_pixel(px as u32, py as u32, DEBUG_PLAYER);
+                }
+            }
+        }
+
+        img
+    }
+
+    /// The screen row where walls at infinite distance converge, i.e. where
+    /// the ceiling and floor bands of every column meet.
+    pub fn horizon_row(&self) -> u32 {
+        let half_height = (self.buffer.height() as f64) / 2.0;
+        clip(half_height.round(), self.buffer.height()).min(self.buffer.height().saturating_sub(1))
+    }
+
+    /// Quantizes the buffer to the colors of `palette`, using a 4x4 ordered
+    /// (Bayer) dither to spread the error across neighbouring pixels.
+    pub fn dither_to_palette(&mut self, palette: &[Rgb<u8>]) {
+        if palette.is_empty() {
+            return;
+        }
+
+        let spread = 255.0 / (palette.len() - 1).max(1) as f64;
+
+        for (x, y, pixel) in self.buffer.enumerate_pixels_mut() {
+            let threshold = (BAYER[(y % 4) as usize][(x % 4) as usize] + 0.5) / 16.0 - 0.5;
+            let nudge = |c: u8| c as f64 + threshold * spread;
+            *pixel = nearest(palette, [nudge(pixel[0]), nudge(pixel[1]), nudge(pixel[2])]);
+        }
+    }
+
+    /// Darkens every `gap`-th row of the buffer, starting with the first,
+    /// by `intensity` between 0 (unchanged) and 1 (black), like the
+    /// scanlines of a CRT screen. A gap of 0 leaves the buffer unchanged.
+    pub fn apply_scanlines(&mut self, intensity: f64, gap: u32) {
+        if gap == 0 {
+            return;
+        }
+        let keep = 1.0 - intensity.clamp(0.0, 1.0);
+        for (_, y, pixel) in self.buffer.enumerate_pixels_mut() {
+            if y % gap == 0 {
+                *pixel = Rgb(pixel.0.map(|c| (c as f64 * keep).round() as u8));
+            }
+        }
+    }
+
+    /// Writes the current buffer as a binary PPM (P6) image.
+    pub fn write_ppm<W: Write>(&self, w: &mut W) -> Result<()> {
+        write!(w, "P6\n{} {}\n255\n", self.buffer.width(), self.buffer.height())?;
+        w.write_all(self.buffer.as_raw())?;
+        Ok(())
+    }
+
+    /// Compares two frames of the same size, such as a render and a golden
+    /// image, so that tests can tolerate small differences.
+    pub fn compare(a: &RgbImage, b: &RgbImage) -> Result<ImageDiff> {
+        if a.dimensions() != b.dimensions() {
+            bail!("cannot compare a {:?} image with a {:?} one", a.dimensions(), b.dimensions());
+        }
+
+        let mut diff = ImageDiff { max_delta: 0, mse: 0.0, differing: 0 };
+        let mut squares = 0u64;
+
+        for (pa, pb) in a.pixels().zip(b.pixels()) {
+            let deltas = [0, 1, 2].map(|c| pa[c].abs_diff(pb[c]));
+            diff.max_delta = deltas.iter().fold(diff.max_delta, |max, &d| max.max(d));
+            squares += deltas.iter().map(|&d| d as u64 * d as u64).sum::<u64>();
+            if deltas != [0, 0, 0] {
+                diff.differing += 1;
+            }
+        }
+
+        let channels = a.as_raw().len();
+        if channels > 0 {
+            diff.mse = squares as f64 / channels as f64;
+        }
+        Ok(diff)
+    }
+
+}
+
+#[cfg(test)]
+fn test_map(header: &str) -> Map {
+    test_map_grid(header, "
+11111
+10001
+10N01
+10001
+11111
+")
+}
+
+#[cfg(test)]
+fn test_map_grid(header: &str, grid: &str) -> Map {
+    let data = format!("
+{}
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+F 220,100,0
+C 225,30,0
+{}", header, grid);
+    Map::load(data.as_bytes()).unwrap()
+}
+
+#[test]
+fn test_write_ppm() {
+    let map = test_map("R 8 6");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+
+    let mut out = Vec::new();
+    r.write_ppm(&mut out).unwrap();
+
+    let header = b"P6\n8 6\n255\n";
+    assert!(out.starts_with(header));
+    assert_eq!(out.len(), header.len() + 8 * 6 * 3);
+}
+
+#[test]
+fn test_horizon_row() {
+    let map = test_map("R 8 7");
+    let r = Render::spawn(&map);
+    assert_eq!(r.horizon_row(), 4);
+
+    let map = test_map("R 8 6");
+    let r = Render::spawn(&map);
+    assert_eq!(r.horizon_row(), 3);
+}
+
+#[test]
+fn test_select_lod() {
+    let near = select_lod(128, 256);
+    let far = select_lod(128, 8);
+
+    assert_eq!(near, 0);
+    assert_eq!(far, 4);
+    assert!(far > near);
+}
+
+#[test]
+fn test_render_poses() {
+    let map = test_map("R 16 12");
+    let mut r = Render::spawn(&map);
+
+    let a = (v(2.5, 2.5), Vector::angle(0.0));
+    let b = (v(1.5, 1.5), Vector::angle(1.0));
+
+    let frames = r.render_poses(&map, &[a, b, a]);
+
+    assert_eq!(frames.len(), 3);
+    assert!(frames.iter().all(|f| f.dimensions() == (16, 12)));
+    assert_eq!(frames[0].as_raw(), frames[2].as_raw());
+    assert_ne!(frames[0].as_raw(), frames[1].as_raw());
+}
+
+#[test]
+fn test_turn_stability() {
+    let map = test_map("R 8 6");
+    let mut r = Render::spawn(&map);
+    let start = r.cam;
+
+    for _ in 0..10_000 {
+        for _ in 0..8 {
+            r.turn(std::f64::consts::FRAC_PI_4);
+        }
+    }
+
+    assert!((r.cam.squared_norm() - 1.0).abs() < 1e-9);
+    assert!(r.cam.squared_distance(&start) < 1e-12);
+}
+
+#[test]
+fn test_debug_topdown() {
+    let map = test_map("R 8 6");
+    let r = Render::spawn(&map);
+
+    let img = r.debug_topdown(&map, 8.0);
+
+    assert_eq!(img.dimensions(), (40, 40));
+    assert_eq!(*img.get_pixel(4, 4), DEBUG_WALL);
+    assert_eq!(*img.get_pixel(20, 20), DEBUG_PLAYER);
+}
+
+#[test]
+fn test_aspect_correction() {
+    let map = test_map("R 64 64");
+    let mut r = Render::spawn(&map);
+    assert!((r.vfov - r.fov).abs() < 1e-12);
+
+    r.set_vfov(45.0);
+    assert!((r.vfov - 45f64.to_radians()).abs() < 1e-12);
+
+    r.set_aspect_correction(true);
+    assert!((r.vfov - r.fov).abs() < 1e-12);
+
+    let map = test_map("R 64 32");
+    let r = Render::spawn(&map);
+    assert!(r.vfov < r.fov);
+}
+
+#[test]
+fn test_columns() {
+    let map = test_map("R 16 12");
+    let mut r = Render::spawn(&map);
+    r.cam = Vector::angle(0.3);
+
+    let mut assembled: RgbImage = ImageBuffer::new(16, 12);
+    for (x, column) in r.columns(&map) {
+        assert_eq!(column.len(), 12);
+        for (y, pixel) in column.into_iter().enumerate() {
+            assembled.put_pixel(x, y as u32, pixel);
+        }
+    }
+
+    r.render(&map);
+    assert_eq!(assembled.as_raw(), r.buffer.as_raw());
+}
+
+#[test]
+fn test_accessors() {
+    let map = test_map("R 64 64");
+    let r = Render::spawn_with_fov(&map, 45.0);
+
+    assert!((r.fov() - 45.0).abs() < 1e-9);
+    assert!((r.vfov() - 45.0).abs() < 1e-9);
+    assert_eq!(r.eye_height(), 0.6);
+}
+
+#[test]
+fn test_dither_to_palette() {
+    let map = test_map("R 64 64");
+    let mut r = Render::spawn(&map);
+    r.buffer = ImageBuffer::from_fn(64, 8, |x, _| {
+        let c = (x * 4) as u8;
+        Rgb([c, c, c])
+    });
+
+    let black = Rgb([0, 0, 0]);
+    let white = Rgb([255, 255, 255]);
+    r.dither_to_palette(&[black, white]);
+
+    assert!(r.buffer.pixels().all(|p| *p == black || *p == white));
+
+    let whites = |xs: std::ops::Range<u32>| xs
+        .flat_map(|x| (0..8).map(move |y| (x, y)))
+        .filter(|&(x, y)| *r.buffer.get_pixel(x, y) == white)
+        .count();
+
+    let middle = whites(28..36);
+    assert!(middle > 0 && middle < 64);
+    assert!(whites(0..16) < whites(48..64));
+}
+
+#[test]
+fn test_pick() {
+    let map = test_map("R 64 64");
+    let r = Render::spawn(&map);
+
+    // The eye is at 0.6 wall heights, so the horizon is 0.4 from the top.
+    let pick = r.pick(&map, 32, 32).unwrap();
+    assert_eq!((pick.x, pick.y, pick.direction), (2, 0, Direction::S));
+    assert!((pick.u - 0.5).abs() < 0.02);
+    assert!((pick.v - 0.4).abs() < 0.02);
+
+    assert_eq!(r.pick(&map, 32, 0), None);
+    assert_eq!(r.pick(&map, 32, 63), None);
+}
+
+#[test]
+fn test_render_with_depth() {
+    let map = test_map("R 64 64");
+    let mut r = Render::spawn(&map);
+
+    let (color, depth) = r.render_with_depth(&map);
+    assert_eq!(color.as_raw(), r.buffer.as_raw());
+    let mut plain = Render::spawn(&map);
+    plain.render(&map);
+    assert_eq!(color, plain.buffer);
+    assert_eq!(depth.get_pixel(32, 0)[0], DEPTH_NONE);
+    let far = depth.get_pixel(32, 32)[0];
+    assert_eq!(far, 1500);
+
+    r.pos = v(2.5, 1.5);
+    let (_, depth) = r.render_with_depth(&map);
+    let near = depth.get_pixel(32, 32)[0];
+    assert_eq!(near, 500);
+    assert!(near < far);
+}
+
+#[test]
+fn test_fog_cell() {
+    let map = test_map_grid("R 64 64", "
+11111
+10001
+10F01
+10N01
+11111
+");
+    let r = Render::spawn(&map);
+
+    let pick = r.pick(&map, 32, 32).unwrap();
+    assert_eq!((pick.x, pick.y), (2, 2));
+    assert!(map.is_walkable(2, 2));
+    assert!(!map.is_walkable(2, 0));
+}
+
+#[test]
+fn test_builder() {
+    let map = test_map("R 64 48");
+    let black = Rgb([0, 0, 0]);
+
+    let r = RenderBuilder::from_map(&map)
+        .fov(60.0)
+        .eye_height(0.5)
+        .fog(black, 0.1)
+        .build()
+        .unwrap();
+
+    assert!((r.fov() - 60.0).abs() < 1e-9);
+    assert_eq!(r.eye_height(), 0.5);
+    assert_eq!(r.fog, Some(Fog { color: black, density: 0.1 }));
+
+    assert!(RenderBuilder::from_map(&map).eye_height(2.0).build().is_err());
+    assert!(RenderBuilder::from_map(&map).fov(0.0).build().is_err());
+}
+
+#[test]
+fn test_debug_floor() {
+    let map = test_map("R 64 64");
+    let mut r = Render::spawn(&map);
+    r.pos = v(2.3, 2.5);
+    r.debug_floor = true;
+    r.render(&map);
+
+    let row = 63;
+    assert!((0..64).all(|x| DEBUG_CHECKER.contains(r.buffer.get_pixel(x, row))));
+    assert!((1..64).any(|x| r.buffer.get_pixel(x - 1, row) != r.buffer.get_pixel(x, row)));
+}
+
+#[test]
+fn test_project() {
+    let map = test_map("R 64 48");
+    let r = Render::spawn(&map);
+
+    let (x, y) = r.project(v(2.5, 1.0)).unwrap();
+    assert!((x - 32.0).abs() < 1e-9);
+    assert!((y - r.horizon_row() as f64).abs() < 1e-9);
+
+    let (x, _) = r.project(v(3.0, 1.0)).unwrap();
+    assert!(x > 32.0);
+
+    let (_, y) = r.project_at(v(2.5, 1.0), 0.0).unwrap();
+    assert!(y > 24.0);
+
+    assert_eq!(r.project(v(2.5, 3.5)), None);
+}
+
+#[test]
+fn test_tinted_wall() {
+    let grid = "
+11a11
+10001
+10N01
+10001
+11111
+";
+    let plain = test_map_grid("R 64 64", grid);
+    let tinted = test_map_grid("R 64 64\nTINT a:255,0,0", grid);
+
+    let mut r = Render::spawn(&plain);
+    r.render(&plain);
+    let expected = r.buffer.clone();
+    r.render(&tinted);
+
+    let column = r.cast(&tinted, 32);
+    let mut reduced = false;
+    for y in column.ceil..column.floor {
+        let (a, b) = (expected.get_pixel(32, y), r.buffer.get_pixel(32, y));
+        assert_eq!((a[0], b[1], b[2]), (b[0], 0, 0));
+        reduced |= a[1] > 0 || a[2] > 0;
+    }
+    assert!(reduced);
+}
+
+#[test]
+fn test_point_blank_wall() {
+    let map = test_map("R 64 64");
+    let mut r = Render::spawn(&map);
+    r.pos = v(2.5, 1.001);
+    r.render(&map);
+
+    let column = r.cast(&map, 32);
+    assert_eq!((column.ceil, column.floor), (0, 64));
+    assert!(column.top.is_finite() && column.bottom.is_finite());
+
+    assert!((0..64).all(|y| r.pick(&map, 32, y).is_some()));
+    assert!((0..64).all(|y| *r.buffer.get_pixel(32, y) != map.ceiling && *r.buffer.get_pixel(32, y) != map.floor));
+}
+
+#[test]
+fn test_spawn_with_resolution() {
+    let map = test_map("R 1920 1080");
+    let mut r = Render::spawn_with_resolution(&map, 320, 240);
+
+    assert_eq!(r.buffer.dimensions(), (320, 240));
+    assert!((r.vfov - aspect_vfov(r.fov, 320, 240)).abs() < 1e-12);
+
+    r.render(&map);
+    assert_eq!(r.buffer.dimensions(), (320, 240));
+}
+
+#[test]
+fn test_sprite_depth_order() {
+    let data = "
+R 64 64
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+S2 tex/fixtures/blue1x1.png
+S3 tex/fixtures/red1x1.png
+F 220,100,0
+C 225,30,0
+
+11111
+10001
+10S01
+10301
+10201
+10001
+11111
+";
+    let map = Map::load(data.as_bytes()).unwrap();
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+
+    // The near red sprite comes first in map order, but is drawn over the
+    // far blue one.
+    assert_eq!(*r.buffer.get_pixel(32, 40), Rgb([255, 0, 0]));
+}
+
+#[test]
+fn test_animation_clock() {
+    let mut map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+
+    r.render(&map);
+    let before = r.buffer.clone();
+    r.advance(0.25);
+    r.render(&map);
+    assert_eq!(r.time(), 0.25);
+    assert!(r.buffer == before);
+
+    for (tex, path) in map.textures.iter_mut().zip(&["north", "south", "west", "east"]) {
+        *tex = Texture::load(format!("tex/{}.png", path)).unwrap().with_scroll(0.5, 0.0);
+    }
+    r.render(&map);
+    let before = r.buffer.clone();
+    r.advance(0.25);
+    r.render(&map);
+    assert!(r.buffer != before);
+}
+
+#[test]
+fn test_texture_voffset() {
+    let plain = test_map("R 64 64");
+    let mut map = test_map("R 64 64");
+    for (tex, path) in map.textures.iter_mut().zip(&["north", "south", "west", "east"]) {
+        *tex = Texture::load(format!("tex/{}.png", path)).unwrap().with_voffset(0.5);
+    }
+
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+
+    let column = r.cast(&map, 32);
+    let tex = plain.texture(column.hit.direction);
+    let lod = select_lod(tex.height(), column.floor - column.ceil);
+    for y in column.ceil..column.floor {
+        let (u, v) = column.uv(y);
+        assert_eq!(*r.buffer.get_pixel(32, y), tex.get_lod((u, v + 0.5), lod));
+    }
+}
+
+#[test]
+fn test_project_wall() {
+    let vfov = 45f64.to_radians();
+
+    // The screen spans two units of height at distance 1, 50 rows each.
+    assert_eq!(project_wall(1.0, 0.55, vfov, 100), (27, 77));
+    assert_eq!(project_wall(2.0, 0.55, vfov, 100), (38, 63));
+    assert_eq!(project_wall(0.1, 0.55, vfov, 100), (0, 100));
+
+    // Far away walls shrink to the horizon row.
+    assert_eq!(project_wall(1e9, 0.6, vfov, 100), (49, 50));
+    assert_eq!(project_wall(f64::INFINITY, 0.6, vfov, 100), (50, 50));
+}
+
+#[test]
+fn test_null_fov() {
+    let map = test_map("R 8 6");
+    assert!(RenderBuilder::from_map(&map).fov(0.0).build().is_err());
+
+    let mut r = Render::spawn_with_fov(&map, 0.0);
+    assert!(r.fov() > 0.0);
+    r.set_fov(-10.0);
+    assert!(r.fov() > 0.0);
+    r.set_fov(120.0);
+    assert!(r.fov() < 90.0);
+
+    r.set_fov(0.0);
+    r.render(&map);
+    assert!(r.ray(0) != r.ray(7));
+}
+
+#[test]
+fn test_visible_cells() {
+    let map = test_map("R 64 48");
+    let mut r = Render::spawn(&map);
+
+    let expected: HashSet<_> = vec![(1, 0), (2, 0), (3, 0)].into_iter().collect();
+    assert_eq!(r.visible_cells(&map), expected);
+
+    r.turn(std::f64::consts::FRAC_PI_4);
+    let turned = r.visible_cells(&map);
+    assert!(turned.contains(&(3, 0)) && turned.contains(&(4, 1)));
+    // The corner is hidden behind the walls next to it.
+    assert!(!turned.contains(&(4, 0)) && !turned.contains(&(1, 0)));
+}
+
+#[test]
+fn test_depth_cue() {
+    let texel = Rgb([200, 120, 40]);
+    assert_eq!(hsv_to_rgb(rgb_to_hsv(texel)), texel);
+    assert_eq!(depth_cue(texel, 3.0, 0.0), texel);
+
+    let (hue, near_s, near_v) = rgb_to_hsv(depth_cue(texel, 1.0, 0.2));
+    let (far_hue, far_s, far_v) = rgb_to_hsv(depth_cue(texel, 5.0, 0.2));
+    assert!(far_s < near_s && far_v < near_v);
+    assert!((hue - far_hue).abs() < 0.05);
+
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+    let plain = r.buffer.clone();
+    r.set_depth_cue(0.5);
+    r.render(&map);
+    let column = r.cast(&map, 16);
+    let hsv = |img: &RgbImage| (column.ceil..column.floor)
+        .map(|y| rgb_to_hsv(*img.get_pixel(16, y)))
+        .fold((0.0, 0.0), |(s, v), (_, si, vi)| (s + si, v + vi));
+    let ((s0, v0), (s1, v1)) = (hsv(&plain), hsv(&r.buffer));
+    assert!(s1 < s0 && v1 < v0);
+}
+
+#[test]
+fn test_render_transparent() {
+    let map = test_map_grid("R 64 48\nG tex/fixtures/glass1x1.png", "
+11111
+10001
+10G01
+10001
+10N01
+11111
+");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+    let background = r.buffer.clone();
+    r.render_transparent(&map);
+
+    let a = 128.0 / 255.0;
+    let pane = r.project_hit(Raycaster::new(r.pos, r.ray(32), grid_of(&map.data))
+        .find(|h| map.data[[h.y, h.x]] == MapCell::Window).unwrap(), r.ray(32));
+    assert!(pane.floor - pane.ceil > 10);
+    for y in 0..48 {
+        let (bg, px) = (background.get_pixel(32, y), r.buffer.get_pixel(32, y));
+        if (pane.ceil..pane.floor).contains(&y) {
+            let blend = |c: u8, glass: f64| ((1.0 - a) * c as f64 + a * glass).round() as u8;
+            assert_eq!(*px, Rgb([blend(bg[0], 0.0), blend(bg[1], 0.0), blend(bg[2], 255.0)]));
+        } else {
+            assert_eq!(px, bg);
+        }
+    }
+}
+
+#[test]
+fn test_sprite_behind_window() {
+    let grid = |window: &str| format!("
+11111
+10201
+10{}01
+10N01
+11111
+", window);
+    let header = "R 64 48\nG tex/fixtures/glass1x1.png\nS2 tex/fixtures/red1x1.png";
+    let (glass, open) = (test_map_grid(header, &grid("G")), test_map_grid(header, &grid("0")));
+    let frame = |map: &Map| {
+        let mut r = Render::spawn(map);
+        r.render_transparent(map);
+        r
+    };
+    let (r, seen) = (frame(&glass), frame(&open).buffer);
+
+    let mut panes = Vec::new();
+    r.cast_through_windows(&mut r.caster(&glass), &glass, r.ray(32), Some(&mut panes));
+    let rows = panes[0].ceil..panes[0].floor;
+    assert!(rows.clone().any(|y| *seen.get_pixel(32, y) == Rgb([255, 0, 0])));
+
+    // The item shows through the glass, blended like the wall around it.
+    let a = 128.0 / 255.0;
+    let blend = |c: u8, glass: f64| ((1.0 - a) * c as f64 + a * glass).round() as u8;
+    for y in rows {
+        let (bg, px) = (seen.get_pixel(32, y), r.buffer.get_pixel(32, y));
+        assert_eq!(*px, Rgb([blend(bg[0], 0.0), blend(bg[1], 0.0), blend(bg[2], 255.0)]));
+    }
+}
+
+#[test]
+fn test_noclip_back_face() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.noclip = true;
+
+    // Inside the north wall, facing the room.
+    r.pos = v(2.5, 0.5);
+    r.turn(std::f64::consts::PI);
+    r.render(&map);
+    let column = r.cast(&map, 16);
+    assert!(column.back_face && column.hit.direction == Direction::S);
+    assert!((column.ceil..column.floor).all(|y| *r.buffer.get_pixel(16, y) == BACK_FACE));
+
+    // Facing out of the map.
+    r.turn(std::f64::consts::PI);
+    r.render(&map);
+    assert_eq!(*r.buffer.get_pixel(16, 12), BACK_FACE);
+
+    // Without noclip, the camera sees through its cell.
+    r.noclip = false;
+    r.turn(std::f64::consts::PI);
+    r.render(&map);
+    assert!(r.buffer.pixels().all(|p| *p != BACK_FACE));
+}
+
+#[test]
+fn test_normalized_depth() {
+    let map = test_map("R 8 6");
+    let mut r = Render::spawn(&map);
+    r.set_depth_range(1.0, 10.0);
+
+    assert_eq!(r.normalized_depth(1.0), 0.0);
+    assert_eq!(r.normalized_depth(10.0), 1.0);
+    assert_eq!(r.normalized_depth(0.5), 0.0);
+    assert_eq!(r.normalized_depth(f64::INFINITY), 1.0);
+
+    // Over half the range is spent within twice the near distance.
+    assert!((r.normalized_depth(2.0) - 5.0 / 9.0).abs() < 1e-12);
+    assert!(r.normalized_depth(3.0) < r.normalized_depth(4.0));
+}
+
+#[test]
+fn test_flat_shading() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.flat_shading = true;
+    r.render(&map);
+
+    let column = r.cast(&map, 16);
+    let color = flat_color(column.hit.direction);
+    assert!(column.floor > column.ceil);
+    assert!((column.ceil..column.floor).all(|y| *r.buffer.get_pixel(16, y) == color));
+
+    let faces = [Direction::N, Direction::S, Direction::E, Direction::W];
+    let colors: HashSet<_> = faces.iter().map(|&d| flat_color(d)).collect();
+    assert_eq!(colors.len(), 4);
+
+    // Wall textures are never sampled, so lazy ones stay undecoded.
+    let lazy = Map::load_lazy(&std::fs::read("sample.cub").unwrap()[..]).unwrap();
+    let mut r = Render::spawn(&lazy);
+    r.flat_shading = true;
+    r.render(&lazy);
+    assert!(lazy.textures.iter().all(|t| !t.is_decoded()));
+}
+
+#[test]
+fn test_fov_header() {
+    let map = test_map("R 8 6\nFOV 60");
+    assert!((Render::spawn(&map).fov() - 60.0).abs() < 1e-9);
+    assert!((RenderBuilder::from_map(&map).build().unwrap().fov() - 60.0).abs() < 1e-9);
+
+    let mut r = Render::spawn(&test_map("R 8 6"));
+    assert!((r.fov() - 30.0).abs() < 1e-9);
+    r.set_fov(45.0);
+    assert!((r.fov() - 45.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_sliding_door() {
+    let grid = "
+11111
+10001
+10D01
+10001
+10N01
+11111
+";
+    let mut map = test_map_grid("R 64 48\nD tex/fixtures/red1x1.png", grid);
+    let red = Rgb([255, 0, 0]);
+    let door_columns = |map: &Map| {
+        let mut r = Render::spawn(map);
+        r.render(map);
+        (0..64).filter(|&x| *r.buffer.get_pixel(x, 24) == red).count()
+    };
+
+    let closed = door_columns(&map);
+    assert!(closed > 20);
+
+    map.set_cell(2, 2, MapCell::Door(128));
+    let half = door_columns(&map) as f64 / closed as f64;
+    assert!((half - 0.5).abs() < 0.1, "half-open door covers {} of its face", half);
+
+    map.set_cell(2, 2, MapCell::Door(DOOR_OPEN));
+    assert_eq!(door_columns(&map), 0);
+}
+
+#[test]
+fn test_shade_background() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+
+    assert_eq!(r.shade_background(&map, 5, 0), map.ceiling);
+    assert_eq!(r.shade_background(&map, 5, 20), map.floor);
+    assert_eq!(*r.buffer.get_pixel(5, 0), map.ceiling);
+
+    r.debug_floor = true;
+    r.render(&map);
+    for (px, py) in [(0, 23), (16, 23), (31, 23)] {
+        assert_eq!(r.shade_background(&map, px, py), *r.buffer.get_pixel(px, py));
+        assert!(DEBUG_CHECKER.contains(&r.shade_background(&map, px, py)));
+    }
+}
+
+#[test]
+fn test_wrap() {
+    let data = "
+R 32 24
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+
+1111111
+0100E00
+1111111
+";
+    let map = Map::load_with_options(data.as_bytes(), &crate::loader::LoadOptions { wrap: true, ..Default::default() }).unwrap();
+    let mut r = Render::spawn(&map);
+
+    // Looking east, the ray leaves through the east edge and hits the wall
+    // just inside the west edge.
+    let column = r.cast(&map, 16);
+    assert_eq!((column.hit.x, column.hit.y, column.hit.direction), (1, 1, Direction::W));
+    assert!((column.depth - 3.5).abs() < 1e-9, "{}", column.depth);
+
+    r.render(&map);
+}
+
+#[test]
+fn test_wrap_open_corridor() {
+    let data = "
+R 32 24
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/sprite.png
+G tex/fixtures/glass1x1.png
+
+1111111
+000E000
+1111111
+";
+    let map = Map::load_with_options(data.as_bytes(), &crate::loader::LoadOptions { wrap: true, ..Default::default() }).unwrap();
+    let mut r = Render::spawn(&map);
+
+    // The center column looks down the corridor forever.
+    let column = r.cast(&map, 16);
+    assert!(column.depth.is_infinite());
+    assert_eq!((column.ceil, column.floor), (r.horizon_row(), r.horizon_row()));
+
+    r.render_background(&map);
+    let background = r.buffer.clone();
+    for render in [Render::render, Render::render_transparent].iter() {
+        render(&mut r, &map);
+        assert!((0..24).all(|y| r.buffer.get_pixel(16, y) == background.get_pixel(16, y)));
+    }
+}
+
+#[test]
+fn test_compare() {
+    let map = test_map("R 16 12");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+
+    let golden = r.buffer.clone();
+    assert_eq!(Render::compare(&r.buffer, &golden).unwrap(),
+               ImageDiff { max_delta: 0, mse: 0.0, differing: 0 });
+
+    let mut off = golden.clone();
+    let Rgb([red, green, blue]) = *off.get_pixel(3, 4);
+    off.put_pixel(3, 4, Rgb([red.wrapping_add(5), green, blue]));
+    let diff = Render::compare(&r.buffer, &off).unwrap();
+    assert_eq!((diff.max_delta, diff.differing), (5, 1));
+    assert!((diff.mse - 25.0 / (16.0 * 12.0 * 3.0)).abs() < 1e-12);
+
+    assert!(Render::compare(&golden, &RgbImage::new(12, 16)).is_err());
+}
+
+#[test]
+fn test_emissive_walls() {
+    let map = test_map_grid("R 32 24\nGLOW l", "
+11l11
+10001
+10N01
+10001
+11111
+");
+    let mut r = Render::spawn(&map);
+    let (glowing, plain) = (r.column(&map, 16), r.column(&map, 0));
+    assert_eq!(r.cast(&map, 16).hit.x, 2);
+    assert_eq!(r.cast(&map, 0).hit.x, 1);
+
+    r.set_fog(Some(Fog { color: Rgb([0, 0, 0]), density: 0.5 }));
+    assert_eq!(r.column(&map, 16), glowing);
+    assert_ne!(r.column(&map, 0), plain);
+}
+
+#[test]
+fn test_render_panorama() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.turn(0.3);
+    let (width, height) = (64, 24);
+
+    let panorama = r.render_panorama(&map, width, height);
+    assert_eq!(panorama.dimensions(), (width, height));
+    assert_eq!(r.buffer.dimensions(), (32, 24));
+
+    // Turning the camera back by one column shifts the panorama right, the
+    // last column wrapping around to the first.
+    let column = |img: &RgbImage, x: u32| (0..height).map(|y| *img.get_pixel(x, y)).collect::<Vec<_>>();
+    r.turn(-std::f64::consts::TAU / width as f64);
+    let turned = r.render_panorama(&map, width, height);
+    assert_eq!(column(&turned, 0), column(&panorama, width - 1));
+    assert_eq!(column(&turned, 1), column(&panorama, 0));
+
+    // From the middle of a square room, the untextured scene is symmetric
+    // about the north axis. Looking half a column east of it puts that axis
+    // on the seam, between the first and last columns.
+    r.cam = Vector::angle(-std::f64::consts::FRAC_PI_2 + std::f64::consts::PI / width as f64);
+    r.flat_shading = true;
+    let panorama = r.render_panorama(&map, width, height);
+    assert_eq!(column(&panorama, 0), column(&panorama, width - 1));
+}
+
+#[test]
+fn test_background_gradient() {
+    let map = test_map("R 32 24\nCG 0,0,64 128,160,255\nFG 40,30,20 100,90,80");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+
+    let (top, horizon) = (r.shade_background(&map, 5, 0), r.shade_background(&map, 5, 11));
+    assert_ne!(top, horizon);
+    assert_eq!(*r.buffer.get_pixel(5, 0), top);
+    assert_eq!(top, blend(Rgb([0, 0, 64]), Rgb([128, 160, 255]), 0.5 / 12.0));
+
+    let (near, far) = (r.shade_background(&map, 5, 23), r.shade_background(&map, 5, 12));
+    assert_eq!(*r.buffer.get_pixel(5, 23), near);
+    assert!(near[0] < far[0], "{:?} {:?}", near, far);
+
+    // Pitching up samples the top row nearer the top of the sky.
+    r.set_sky_pitch(0.1);
+    assert!(r.sky_v(0.5) < 0.5 / 12.0);
+    assert_eq!(r.shade_background(&map, 5, 0), blend(Rgb([0, 0, 64]), Rgb([128, 160, 255]), r.sky_v(0.5)));
+    assert_ne!(r.shade_background(&map, 5, 0), top);
+    assert_eq!(r.shade_background(&map, 5, 23), near);
+}
+
+#[test]
+fn test_render_shaded() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+    let plain = r.buffer.clone();
+
+    r.render_shaded(&map, |_, _, _| Rgb([255, 0, 0]));
+    let column = r.cast(&map, 16);
+    for y in 0..24 {
+        let expected = if (column.ceil..column.floor).contains(&y) { Rgb([255, 0, 0]) } else { *plain.get_pixel(16, y) };
+        assert_eq!(*r.buffer.get_pixel(16, y), expected);
+    }
+
+    r.render_shaded(&map, |_, _, pixel| pixel);
+    assert!(r.buffer == plain);
+}
+
+#[test]
+fn test_sprite_alpha() {
+    let data = |item: char| format!("
+R 64 64
+NO tex/north.png
+SO tex/south.png
+WE tex/west.png
+EA tex/east.png
+S tex/fixtures/glass2x1.png
+F 220,100,0
+C 225,30,0
+
+11111
+10001
+10S01
+10001
+10{}01
+10001
+11111
+", item);
+    let map = Map::load(data('2').as_bytes()).unwrap();
+    let empty = Map::load(data('0').as_bytes()).unwrap();
+    assert!(map.sprite.has_alpha());
+
+    let mut r = Render::spawn(&map);
+    r.render(&empty);
+    let behind = r.buffer.clone();
+    r.render(&map);
+
+    // The left half of the sprite is half transparent blue, the right
+    // half fully transparent.
+    assert_eq!(*r.buffer.get_pixel(24, 34), blend(*behind.get_pixel(24, 34), Rgb([0, 0, 255]), 128.0 / 255.0));
+    assert_ne!(*r.buffer.get_pixel(24, 34), *behind.get_pixel(24, 34));
+    assert_eq!(*r.buffer.get_pixel(40, 34), *behind.get_pixel(40, 34));
+}
+
+#[test]
+fn test_render_parallel() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let map = Map::load(&data[..]).unwrap();
+    let mut r = Render::spawn_with_resolution(&map, 160, 100);
+
+    r.render(&map);
+    let serial = r.buffer.clone();
+    for &threads in &[1, 3, 4, 200] {
+        r.buffer = ImageBuffer::new(160, 100);
+        r.render_parallel(&map, threads);
+        assert!(r.buffer == serial, "{} threads", threads);
+    }
+}
+
+#[test]
+fn test_fisheye_correction() {
+    let map = test_map("R 64 48");
+    let mut r = Render::spawn(&map);
+    assert!(r.fisheye_correction());
+
+    let height = |r: &Render, x| { let c = r.cast(&map, x); c.floor - c.ceil };
+    assert_eq!(height(&r, 0), height(&r, 32));
+
+    // Facing a flat wall, the raw distance shrinks the edges of the screen.
+    r.set_fisheye_correction(false);
+    assert!(height(&r, 0) < height(&r, 32));
+    r.render(&map);
+}
+
+#[test]
+fn test_angular_shading() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.flat_shading = true;
+
+    // Both columns see the north wall, the edge one at a 30° angle.
+    assert_eq!(r.cast(&map, 0).hit.direction, r.cast(&map, 16).hit.direction);
+    let row = |r: &Render, x| { let c = r.cast(&map, x); r.column(&map, x)[(c.ceil + c.floor) as usize / 2] };
+    assert_eq!(row(&r, 0), row(&r, 16));
+
+    r.set_angular_shading(1.0);
+    let (grazing, head_on) = (row(&r, 0), row(&r, 16));
+    let flat = flat_color(Direction::S);
+    assert!(grazing.0.iter().zip(&head_on.0).all(|(g, h)| g <= h));
+    assert!(grazing != head_on);
+    assert_eq!(head_on, flat);
+}
+
+#[test]
+fn test_tile_columns() {
+    let data = std::fs::read("sample.cub").unwrap();
+    let map = Map::load(&data[..]).unwrap();
+    let mut r = Render::spawn_with_resolution(&map, 200, 120);
+
+    r.set_tile_columns(1);
+    r.render(&map);
+    let columns = r.buffer.clone();
+
+    for &tile in &[7, 32, 500] {
+        r.buffer = ImageBuffer::new(200, 120);
+        r.set_tile_columns(tile);
+        r.render(&map);
+        assert!(r.buffer == columns, "{} column tiles", tile);
+    }
+}
+
+#[test]
+fn test_linear_shading() {
+    // Half way into a black fog, mid gray keeps more light when blended
+    // linearly.
+    let fog = Fog { color: Rgb([0, 0, 0]), density: 2f64.ln() };
+    let gray = Rgb([128, 128, 128]);
+    assert_eq!(fog.apply(gray, 1.0), Rgb([64, 64, 64]));
+    let linear = fog.apply_linear(gray, 1.0);
+    assert!(linear[0] > 64 && linear[0] < 128, "{:?}", linear);
+
+    for c in 0..=255 {
+        assert_eq!(gamma().srgb(gamma().linear(Rgb([c, c, c]))), Rgb([c, c, c]));
+    }
+
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.set_fog(Some(fog));
+    let naive = r.column(&map, 16);
+    r.set_linear_shading(true);
+    assert_ne!(r.column(&map, 16), naive);
+    r.set_fog(None);
+    r.set_linear_shading(false);
+    let plain = r.column(&map, 16);
+    r.set_linear_shading(true);
+    assert_eq!(r.column(&map, 16), plain);
+}
+
+#[test]
+fn test_spawn_facing() {
+    // A pillar north west of the spawn point tells left from right.
+    let grid = "
+1111111
+1100001
+10X0001
+1000001
+1111111
+";
+    let cases = [
+        ('N', (2, 0), Direction::S),
+        ('S', (2, 4), Direction::N),
+        ('W', (0, 2), Direction::E),
+        ('E', (6, 2), Direction::W),
+    ];
+    for &(letter, cell, face) in cases.iter() {
+        let map = test_map_grid("R 32 24", &grid.replace('X', &letter.to_string()));
+        let mut r = Render::spawn(&map);
+        r.flat_shading = true;
+        r.render(&map);
+        let ahead = r.cast(&map, 16).hit;
+        assert_eq!((ahead.x, ahead.y, ahead.direction), (cell.0, cell.1, face), "facing {}", letter);
+        for x in 15..17 {
+            assert_eq!(*r.buffer.get_pixel(x, 12), flat_color(face), "facing {}", letter);
+        }
+    }
+
+    // Facing north, the pillar is on the left edge of the screen.
+    let map = test_map_grid("R 32 24", &grid.replace('X', "N"));
+    let r = Render::spawn(&map);
+    let left = r.cast(&map, 0).hit;
+    assert_eq!((left.x, left.y), (1, 1));
+    assert_ne!((r.cast(&map, 31).hit.x, r.cast(&map, 31).hit.y), (1, 1));
+}
+
+#[test]
+fn test_render_background() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.render_background(&map);
+    for (_, y, &pixel) in r.buffer.enumerate_pixels() {
+        let expected = if y < 12 { map.ceiling } else { map.floor };
+        assert_eq!(pixel, expected);
+    }
+
+    let map = test_map("R 32 24\nCG 0,0,0 100,100,100\nFG 200,200,200 50,50,50");
+    let mut r = Render::spawn(&map);
+    r.render_background(&map);
+    for (x, y, &pixel) in r.buffer.enumerate_pixels() {
+        assert_eq!(pixel, r.shade_background(&map, x, y));
+    }
+}
+
+#[test]
+fn test_stop_on_opaque() {
+    let hit = Hit { x: 1, y: 1, direction: Direction::N, position: 0.9, squared_distance: 1.0 };
+    let cells = [
+        MapCell::Space, MapCell::Wall, MapCell::Item(2), MapCell::Fog, MapCell::TaggedWall(b'a'),
+        MapCell::Window, MapCell::Door(0), MapCell::Door(128), MapCell::Door(DOOR_OPEN),
+    ];
+    for &cell in cells.iter() {
+        assert_eq!(stop(cell, hit).is_some(), cell.is_opaque(), "{:?}", cell);
+    }
+}
+
+#[test]
+fn test_billboard_axis() {
+    let grid = "
+111111111
+100000001
+100000001
+100000001
+1000200N1
+100000001
+100000001
+100000001
+111111111
+";
+    let width = |mode: &str, angle: f64| {
+        let map = test_map_grid(&format!("R 64 48\nS2 tex/fixtures/red1x1.png\nBB 2:{}", mode), grid);
+        let mut r = Render::spawn(&map);
+        let dir = Vector::angle(angle.to_radians());
+        r.pos = v(4.5, 4.5) - dir * 3.0;
+        r.cam = dir;
+        r.render(&map);
+        (0..64).filter(|&x| *r.buffer.get_pixel(x, 24) == Rgb([255, 0, 0])).count() as i64
+    };
+
+    // Facing the sprite head on, both are as wide.
+    let (facing, fixed) = (width("V", 90.0), width("X", 90.0));
+    assert!(facing > 0 && (facing - fixed).abs() <= 1, "{} {}", facing, fixed);
+
+    // Seen at an angle, only the fixed sprite is foreshortened.
+    assert!((width("V", 40.0) - facing).abs() <= 1);
+    assert!(width("X", 40.0) < fixed * 3 / 4);
+    assert!((width("Y", 0.0) - facing).abs() <= 1);
+}
+
+#[test]
+fn test_short_walls() {
+    let grid = "
+11111
+10a01
+10001
+10N01
+11111
+";
+    let full = test_map_grid("R 32 24", grid);
+    let half = test_map_grid("R 32 24\nWH a:0.75", grid);
+    let (mut r, mut s) = (Render::spawn(&full), Render::spawn(&half));
+    r.render(&full);
+    s.render(&half);
+
+    let (wall, short) = (r.cast(&full, 16), s.cast(&half, 16));
+    assert_eq!((wall.hit.x, wall.hit.y), (2, 1));
+    assert_eq!((short.top, short.bottom, short.floor), (wall.top, wall.bottom, wall.floor));
+    assert!(short.ceil > wall.ceil && short.ceil < short.floor);
+
+    // Above the short wall, where the full wall is textured, the ceiling
+    // shows; below it both walls look the same.
+    assert!(short.ceil < 12);
+    for y in wall.ceil..short.ceil {
+        assert_eq!(*s.buffer.get_pixel(16, y), full.ceiling);
+        assert_ne!(*r.buffer.get_pixel(16, y), full.ceiling);
+    }
+    for y in short.ceil..short.floor {
+        assert_eq!(s.buffer.get_pixel(16, y), r.buffer.get_pixel(16, y));
+    }
+
+    // Short walls are drawn the same way when rendering windows.
+    let glass = test_map_grid("R 32 24\nWH a:0.75\nG tex/fixtures/glass1x1.png", grid);
+    let mut t = Render::spawn(&glass);
+    t.render_transparent(&glass);
+    assert_eq!(t.buffer, s.buffer);
+}
+
+#[test]
+fn test_draw_compass() {
+    let map = test_map("R 64 48");
+    let marker = |angle: f64| {
+        let mut r = Render::spawn(&map);
+        r.cam = Vector::angle(angle.to_radians());
+        r.render_background(&map);
+        r.draw_compass((32, 24), 16);
+        let marked: Vec<_> = r.buffer.enumerate_pixels()
+            .filter(|&(_, _, &p)| p == COMPASS_NORTH)
+            .map(|(x, y, _)| (x as f64, y as f64))
+            .collect();
+        assert!(!marked.is_empty());
+        assert_eq!(*r.buffer.get_pixel(32 + 16, 24), COMPASS_RING);
+        let n = marked.len() as f64;
+        (marked.iter().map(|p| p.0).sum::<f64>() / n - 32.0, marked.iter().map(|p| p.1).sum::<f64>() / n - 24.0)
+    };
+
+    // Facing east, north is to the left; facing north, it is ahead.
+    let (x, y) = marker(0.0);
+    assert!(x < -5.0 && y.abs() < 2.0, "{} {}", x, y);
+    let (x, y) = marker(270.0);
+    assert!(y < -5.0 && x.abs() < 2.0, "{} {}", x, y);
+    let (x, _) = marker(180.0);
+    assert!(x > 5.0);
+}
+
+#[test]
+fn test_vertical_scale() {
+    let map = test_map("R 64 48");
+    let mut r = Render::spawn(&map);
+    r.pos = v(2.5, 3.5);
+    let plain = r.cast(&map, 32);
+    r.set_vertical_scale(2.0);
+    let tall = r.cast(&map, 32);
+    assert!(((tall.bottom - tall.top) - 2.0 * (plain.bottom - plain.top)).abs() < 1e-9);
+    assert!(tall.ceil < plain.ceil && tall.floor > plain.floor);
+
+    r.set_vertical_scale(-1.0);
+    assert_eq!(r.vertical_scale, VERTICAL_SCALE_RANGE.0);
+    r.set_vertical_scale(1.0);
+    assert_eq!(r.cast(&map, 32).top, plain.top);
+}
+
+#[test]
+fn test_apply_scanlines() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+    let frame = r.buffer.clone();
+
+    r.apply_scanlines(0.5, 2);
+    for (x, y, &pixel) in r.buffer.enumerate_pixels() {
+        let original = *frame.get_pixel(x, y);
+        if y % 2 == 0 {
+            assert_eq!(pixel, Rgb(original.0.map(|c| (c as f64 * 0.5).round() as u8)));
+        } else {
+            assert_eq!(pixel, original);
+        }
+    }
+
+    r.buffer = frame.clone();
+    r.apply_scanlines(0.5, 0);
+    r.apply_scanlines(0.0, 3);
+    assert!(r.buffer == frame);
+}
+
+#[test]
+fn test_roll() {
+    let map = test_map("R 48 32");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+    let upright = r.buffer.clone();
+    r.set_roll(0.0);
+    r.render(&map);
+    assert!(r.buffer == upright);
+
+    // A quarter turn brings the ceiling at the top center round to the
+    // right. The corners are cleared.
+    r.set_roll(std::f64::consts::FRAC_PI_2);
+    r.render(&map);
+    assert_eq!(*upright.get_pixel(24, 0), map.ceiling);
+    assert_ne!(*upright.get_pixel(24 + 15, 16), map.ceiling);
+    assert_eq!(*r.buffer.get_pixel(24 + 15, 16), map.ceiling);
+    assert_eq!(*r.buffer.get_pixel(0, 0), Rgb([0, 0, 0]));
+
+    // The depth image turns with the frame.
+    r.set_roll(0.0);
+    let (_, flat) = r.render_with_depth(&map);
+    r.set_roll(std::f64::consts::FRAC_PI_2);
+    let (color, depth) = r.render_with_depth(&map);
+    assert_eq!(color, r.buffer);
+    assert_ne!(flat.get_pixel(24 + 15, 16)[0], DEPTH_NONE);
+    assert_eq!(depth.get_pixel(24 + 15, 16)[0], DEPTH_NONE);
+    assert_eq!(depth.get_pixel(24, 16), flat.get_pixel(24, 16));
+    assert_eq!(depth.get_pixel(0, 0)[0], DEPTH_NONE);
+
+    r.set_fog(Some(Fog { color: Rgb([10, 20, 30]), density: 0.1 }));
+    r.render(&map);
+    assert_eq!(*r.buffer.get_pixel(47, 31), Rgb([10, 20, 30]));
+}
+
+#[test]
+fn test_floor_row_distance() {
+    let map = test_map("R 64 48");
+    let r = Render::spawn(&map);
+    assert_eq!(r.floor_row_distance(0), f64::INFINITY);
+    assert_eq!(r.floor_row_distance(23), f64::INFINITY);
+
+    let distances: Vec<_> = (24..48).map(|y| r.floor_row_distance(y)).collect();
+    assert!(distances.iter().all(|d| d.is_finite() && *d > 0.0));
+    assert!(distances.windows(2).all(|w| w[0] > w[1]), "{:?}", distances);
+
+    // Floor rows below the bottom of the center column's wall are nearer
+    // than the wall, rows above it farther.
+    let column = r.cast(&map, 32);
+    for y in 24..48 {
+        let nearer = y as f64 + 0.5 > column.bottom;
+        assert_eq!(r.floor_row_distance(y) < column.depth, nearer, "row {}", y);
+    }
+}
+
+#[test]
+fn test_render_viewport() {
+    let map = test_map("R 32 24");
+    let mut r = Render::spawn(&map);
+    r.render(&map);
+    let (screen, pos, cam) = (r.buffer.clone(), r.pos, r.cam);
+
+    let pose = (v(2.5, 1.5), v(0.0, 1.0));
+    for &(w, h) in [(16, 12), (40, 10)].iter() {
+        let mut dest = RgbImage::new(w, h);
+        r.render_viewport(&map, pose, &mut dest);
+
+        let mut alone = Render::spawn_with_resolution(&map, w, h);
+        alone.pos = pose.0;
+        alone.cam = pose.1;
+        alone.render(&map);
+        assert!(dest == alone.buffer, "{}x{}", w, h);
+    }
+
+    assert!(r.buffer == screen);
+    assert_eq!((r.pos, r.cam), (pos, cam));
+    r.render(&map);
+    assert!(r.buffer == screen);
+}
@@ -1,34 +1,515 @@
-use std::{fs::File, io::BufReader, path::Path};
+use std::{path::{Path, PathBuf}, sync::OnceLock};
 
 use anyhow::{Result, bail};
-use image::{DynamicImage, Rgb, RgbImage};
+use image::{imageops, DynamicImage, GrayImage, ImageBuffer, Luma, Pixel, Rgb, RgbImage, Rgba, RgbaImage};
+#[cfg(feature = "mipmap")]
+use image::imageops::FilterType;
 
 
+/// How texture coordinates outside `[0, 1)` are mapped onto the texture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WrapMode {
+    /// Tile the texture, for walls.
+    Repeat,
+    /// Stick to the nearest edge texel, for sprites and decals.
+    Clamp,
+}
+
+/// A reorientation applied to a texture when it is decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transform {
+    FlipX,
+    FlipY,
+    /// Clockwise rotations.
+    Rot90,
+    Rot180,
+    Rot270,
+}
+
+impl Transform {
+    /// Parses the name used in map headers: `flipx`, `flipy`, `rot90`,
+    /// `rot180` or `rot270`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "flipx" => Some(Transform::FlipX),
+            "flipy" => Some(Transform::FlipY),
+            "rot90" => Some(Transform::Rot90),
+            "rot180" => Some(Transform::Rot180),
+            "rot270" => Some(Transform::Rot270),
+            _ => None,
+        }
+    }
+
+    fn apply<P: Pixel + 'static>(self, img: &ImageBuffer<P, Vec<P::Subpixel>>) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        match self {
+            Transform::FlipX => imageops::flip_horizontal(img),
+            Transform::FlipY => imageops::flip_vertical(img),
+            Transform::Rot90 => imageops::rotate90(img),
+            Transform::Rot180 => imageops::rotate180(img),
+            Transform::Rot270 => imageops::rotate270(img),
+        }
+    }
+}
+
 pub struct Texture {
-    inner: RgbImage
+    image: OnceLock<Image>,
+    /// The file to decode `image` from on first use, for lazy textures.
+    path: Option<PathBuf>,
+    /// Applied in order to lazy textures once decoded.
+    transforms: Vec<Transform>,
+    wrap: WrapMode,
+    scroll: (f64, f64),
+    voffset: f64,
+    /// The map header value the texture was declared with.
+    source: Option<String>,
+}
+
+/// The decoded texels of a texture.
+struct Image {
+    inner: RgbImage,
+    /// The opacity of every texel, for images with an alpha channel.
+    alpha: Option<GrayImage>,
+    #[cfg(feature = "mipmap")]
+    mips: Vec<RgbImage>,
+}
+
+impl Image {
+    fn new((inner, alpha): (RgbImage, Option<GrayImage>)) -> Self {
+        Image {
+            #[cfg(feature = "mipmap")]
+            mips: pyramid(&inner),
+            inner,
+            alpha,
+        }
+    }
+
+    fn transformed(self, transforms: &[Transform]) -> Self {
+        if transforms.is_empty() {
+            return self;
+        }
+        let inner = transforms.iter().fold(self.inner, |img, t| t.apply(&img));
+        let alpha = self.alpha.map(|alpha| transforms.iter().fold(alpha, |img, t| t.apply(&img)));
+        Image::new((inner, alpha))
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    /// The number of images decoded by the current thread.
+    static DECODES: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+fn decode(data: &[u8]) -> Result<(RgbImage, Option<GrayImage>)> {
+    #[cfg(test)]
+    DECODES.with(|n| n.set(n.get() + 1));
+
+    let img = image::load_from_memory(data)?;
+    match img {
+        DynamicImage::ImageRgb8(inner) => Ok((inner, None)),
+        DynamicImage::ImageRgba8(rgba) => Ok(split_alpha(rgba)),
+        DynamicImage::ImageRgb16(_) => Ok((img.to_rgb8(), None)),
+        DynamicImage::ImageRgba16(_) => Ok(split_alpha(img.to_rgba8())),
+        _ => bail!("Unsupported texture format"),
+    }
+}
+
+/// Splits an RGBA image into its colors and its alpha plane.
+fn split_alpha(rgba: RgbaImage) -> (RgbImage, Option<GrayImage>) {
+    let alpha = GrayImage::from_fn(rgba.width(), rgba.height(), |x, y| Luma([rgba.get_pixel(x, y)[3]]));
+    (DynamicImage::ImageRgba8(rgba).to_rgb8(), Some(alpha))
+}
+
+/// A splitmix64 generator, so that procedural textures are reproducible
+/// without a dependency.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Successively halved copies of `img`, down to a single texel.
+#[cfg(feature = "mipmap")]
+fn pyramid(img: &RgbImage) -> Vec<RgbImage> {
+    let mut mips: Vec<RgbImage> = Vec::new();
+    let (mut w, mut h) = img.dimensions();
+
+    while w > 1 || h > 1 {
+        w = (w / 2).max(1);
+        h = (h / 2).max(1);
+        let level = imageops::resize(mips.last().unwrap_or(img), w, h, FilterType::Triangle);
+        mips.push(level);
+    }
+
+    mips
+}
+
+fn sample<P: Pixel + 'static>(img: &ImageBuffer<P, Vec<P::Subpixel>>, (x,y): (f64, f64), wrap: WrapMode) -> P {
+    let x = (x * (img.width() as f64).floor()) as u32;
+    let y = (y * (img.height() as f64).floor()) as u32;
+
+    let (x, y) = match wrap {
+        WrapMode::Repeat => (x % img.width(), y % img.height()),
+        WrapMode::Clamp => (x.min(img.width() - 1), y.min(img.height() - 1)),
+    };
+
+
+    *img.get_pixel(x, y)
 }
 
 
 impl Texture {
 
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let source = BufReader::new(File::open(path)?);
-        let img  = image::load(source, image::ImageFormat::Png)?;
-        match img {
-            DynamicImage::ImageRgb8(inner) => Ok(Texture { inner }),
-            _ => bail!("Unsupported texture format"),
+    fn new(image: OnceLock<Image>, path: Option<PathBuf>) -> Self {
+        Texture {
+            image,
+            path,
+            transforms: Vec::new(),
+            wrap: WrapMode::Repeat,
+            scroll: (0.0, 0.0),
+            voffset: 0.0,
+            source: None,
+        }
+    }
+
+    /// Sets how out-of-range texture coordinates are handled. Textures
+    /// repeat by default.
+    pub fn with_wrap(mut self, wrap: WrapMode) -> Self {
+        self.wrap = wrap;
+        self
+    }
+
+    pub fn wrap(&self) -> WrapMode {
+        self.wrap
+    }
+
+    /// Reorients the texture by applying `transforms` in order. Lazy textures
+    /// are transformed when they are decoded.
+    pub fn with_transforms(mut self, transforms: &[Transform]) -> Self {
+        match self.image.take() {
+            Some(image) => self.image = OnceLock::from(image.transformed(transforms)),
+            None => self.transforms.extend_from_slice(transforms),
+        }
+        self
+    }
+
+    /// Animates the texture by scrolling it `(u, v)` texture widths and
+    /// heights per unit of time. Textures are static by default.
+    pub fn with_scroll(mut self, u: f64, v: f64) -> Self {
+        self.scroll = (u, v);
+        self
+    }
+
+    /// Shifts the texture down walls by `voffset` texture heights, to line
+    /// up trims and decals with a given world height.
+    pub fn with_voffset(mut self, voffset: f64) -> Self {
+        self.voffset = voffset;
+        self
+    }
+
+    pub fn voffset(&self) -> f64 {
+        self.voffset
+    }
+
+    /// Records the map header value, a path and modifiers, that declares
+    /// this texture, for writing the map back out.
+    pub fn with_source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_owned());
+        self
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    fn at(&self, (u, v): (f64, f64), time: f64) -> (f64, f64) {
+        if self.scroll == (0.0, 0.0) {
+            return (u, v);
         }
+        let u = u + self.scroll.0 * time;
+        let v = v + self.scroll.1 * time;
+        // Keep coordinates positive for `sample`, which truncates them.
+        match self.wrap {
+            WrapMode::Repeat => (u.rem_euclid(1.0), v.rem_euclid(1.0)),
+            WrapMode::Clamp => (u, v),
+        }
+    }
+
+    /// Reads and decodes the texture at `path`. An alpha channel, if the
+    /// image has one, is kept and read by `get_rgba`: sprites and windows
+    /// load this way like walls do, rather than through a loader of their
+    /// own, and the sprite pass blends them by it where `has_alpha` holds.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
     } 
 
-    pub fn get(&self, (x,y): (f64, f64)) -> Rgb<u8> {
-        let x = (x * (self.inner.width() as f64).floor()) as u32;
-        let y = (y * (self.inner.height() as f64).floor()) as u32;
+    /// A texture read from `path` and decoded the first time it is sampled,
+    /// rather than now. Only the existence of the file is checked here.
+    pub fn lazy<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.is_file() {
+            bail!("{} is not a file", path.display());
+        }
+        Ok(Texture::new(OnceLock::new(), Some(path.to_owned())))
+    }
 
-        let x = x % self.inner.width();
-        let y = y % self.inner.height();
+    /// Decodes a texture from an in-memory buffer, guessing the image format
+    /// from its contents.
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Ok(Texture::new(OnceLock::from(Image::new(decode(data)?)), None))
+    }
+
+    /// A `size`x`size` texture of `base` with every texel lightened or
+    /// darkened by up to `variance`, from a pseudo-random sequence seeded by
+    /// `seed`. The same arguments always give the same texels. A `size` of 0
+    /// gives a single texel.
+    pub fn from_noise(seed: u64, base: Rgb<u8>, variance: u8, size: u32) -> Self {
+        let size = size.max(1);
+        let mut rng = SplitMix(seed);
+        let span = 2 * variance as u64 + 1;
+        let img = RgbImage::from_fn(size, size, |_, _| {
+            let offset = (rng.next() % span) as i16 - variance as i16;
+            Rgb(base.0.map(|c| (c as i16 + offset).clamp(0, 255) as u8))
+        });
+        Texture::new(OnceLock::from(Image::new((img, None))), None)
+    }
+
+    /// Whether the texels have been decoded yet, which lazy textures only do
+    /// when first used.
+    pub fn is_decoded(&self) -> bool {
+        self.image.get().is_some()
+    }
+
+    /// The decoded texels, decoding lazy textures on first use.
+    ///
+    /// # Panics
+    ///
+    /// If a lazy texture can no longer be read or decoded.
+    fn image(&self) -> &Image {
+        self.image.get_or_init(|| {
+            let path = self.path.as_ref().expect("texture without texels nor path");
+            let decoded = std::fs::read(path).map_err(Into::into).and_then(|data| decode(&data));
+            match decoded {
+                Ok(decoded) => Image::new(decoded).transformed(&self.transforms),
+                Err(e) => panic!("decoding texture {}: {}", path.display(), e),
+            }
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.image().inner.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.image().inner.height()
+    }
+
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.image().inner.dimensions()
+    }
+
+    /// A copy of the texture scaled down to fit within `max`x`max` texels,
+    /// keeping its aspect ratio, for previews. Smaller textures are copied
+    /// as they are.
+    pub fn thumbnail(&self, max: u32) -> RgbImage {
+        let (w, h) = self.dimensions();
+        let scale = (max as f64 / w.max(h) as f64).min(1.0);
+        let fit = |n: u32| (n as f64 * scale).round().max(1.0) as u32;
+        imageops::thumbnail(&self.image().inner, fit(w), fit(h))
+    }
+
+    pub fn get(&self, uv: (f64, f64)) -> Rgb<u8> {
+        sample(&self.image().inner, uv, self.wrap)
+    }
+
+    /// Samples a texel with its opacity. Textures decoded without an alpha
+    /// channel are fully opaque.
+    pub fn get_rgba(&self, uv: (f64, f64)) -> Rgba<u8> {
+        let image = self.image();
+        let Rgb([r, g, b]) = sample(&image.inner, uv, self.wrap);
+        let a = image.alpha.as_ref().map_or(255, |alpha| sample(alpha, uv, self.wrap)[0]);
+        Rgba([r, g, b, a])
+    }
+
+    /// Like `get_rgba`, as animated at `time`.
+    pub fn get_rgba_at(&self, uv: (f64, f64), time: f64) -> Rgba<u8> {
+        self.get_rgba(self.at(uv, time))
+    }
+
+    /// Whether the texture was decoded from an image with an alpha channel.
+    pub fn has_alpha(&self) -> bool {
+        self.image().alpha.is_some()
+    }
+
+    /// Samples the texture as animated at `time`.
+    pub fn get_at(&self, uv: (f64, f64), time: f64) -> Rgb<u8> {
+        self.get(self.at(uv, time))
+    }
+
+    /// Samples the mipmap level `lod` of the texture as animated at `time`.
+    pub fn get_lod_at(&self, uv: (f64, f64), lod: usize, time: f64) -> Rgb<u8> {
+        self.get_lod(self.at(uv, time), lod)
+    }
+
+    /// Samples the mipmap level `lod`, where level 0 is the full-size texture
+    /// and each further level halves its dimensions. Levels past the smallest
+    /// one sample the smallest. Without the `mipmap` feature this is `get`.
+    #[cfg(feature = "mipmap")]
+    pub fn get_lod(&self, uv: (f64, f64), lod: usize) -> Rgb<u8> {
+        match lod {
+            0 => self.get(uv),
+            n => {
+                let mips = &self.image().mips;
+                match mips.len() {
+                    // A single texel has no smaller levels.
+                    0 => self.get(uv),
+                    len => sample(&mips[(n - 1).min(len - 1)], uv, self.wrap),
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "mipmap"))]
+    pub fn get_lod(&self, uv: (f64, f64), _lod: usize) -> Rgb<u8> {
+        self.get(uv)
+    }
+
+}
 
+#[test]
+fn test_from_bytes() {
+    let tex = Texture::from_bytes(include_bytes!("../tex/fixtures/rgb2x2.png")).unwrap();
 
-        *self.inner.get_pixel(x, y)
+    assert_eq!(tex.get((0.0, 0.0)), Rgb([255, 0, 0]));
+    assert_eq!(tex.get((0.5, 0.0)), Rgb([0, 255, 0]));
+    assert_eq!(tex.get((0.0, 0.5)), Rgb([0, 0, 255]));
+    assert_eq!(tex.get((0.75, 0.75)), Rgb([255, 255, 255]));
+}
+
+#[test]
+fn test_from_bytes_16bit() {
+    let tex = Texture::from_bytes(include_bytes!("../tex/fixtures/rgb16.png")).unwrap();
+
+    assert_eq!(tex.get((0.0, 0.0)), Rgb([255, 0, 128]));
+}
+
+#[test]
+fn test_wrap_modes() {
+    let data = include_bytes!("../tex/fixtures/rgb2x2.png");
+
+    let repeat = Texture::from_bytes(data).unwrap();
+    assert_eq!(repeat.wrap(), WrapMode::Repeat);
+    assert_eq!(repeat.get((1.2, 0.0)), Rgb([255, 0, 0]));
+
+    let clamp = Texture::from_bytes(data).unwrap().with_wrap(WrapMode::Clamp);
+    assert_eq!(clamp.get((1.2, 0.0)), Rgb([0, 255, 0]));
+    assert_eq!(clamp.get((1.2, 1.2)), Rgb([255, 255, 255]));
+}
+
+#[test]
+fn test_scroll() {
+    let data = include_bytes!("../tex/fixtures/rgb2x2.png");
+
+    let still = Texture::from_bytes(data).unwrap();
+    assert_eq!(still.get_at((0.0, 0.0), 0.0), still.get_at((0.0, 0.0), 10.0));
+
+    let scrolled = Texture::from_bytes(data).unwrap().with_scroll(0.5, 0.0);
+    assert_eq!(scrolled.get_at((0.0, 0.0), 0.0), Rgb([255, 0, 0]));
+    assert_eq!(scrolled.get_at((0.0, 0.0), 1.0), Rgb([0, 255, 0]));
+    assert_eq!(scrolled.get_at((0.0, 0.0), -1.0), Rgb([0, 255, 0]));
+}
+
+#[test]
+fn test_lazy() {
+    let decodes = || DECODES.with(|n| n.get());
+    let before = decodes();
+
+    let tex = Texture::lazy("tex/fixtures/rgb2x2.png").unwrap().with_wrap(WrapMode::Clamp);
+    assert!(!tex.is_decoded());
+    assert_eq!(decodes(), before);
+
+    assert_eq!(tex.get((0.5, 0.0)), Rgb([0, 255, 0]));
+    assert_eq!(tex.get((0.0, 0.5)), Rgb([0, 0, 255]));
+    assert!(tex.is_decoded());
+    assert_eq!(decodes(), before + 1);
+
+    assert!(Texture::lazy("tex/fixtures/missing.png").is_err());
+}
+
+#[test]
+fn test_transforms() {
+    let north = Texture::load("tex/north.png").unwrap();
+    let flipped = Texture::load("tex/north.png").unwrap().with_transforms(&[Transform::FlipX]);
+    let last = 127.0 / 128.0;
+    assert_eq!(flipped.get((0.0, 0.0)), north.get((last, 0.0)));
+
+    let data = include_bytes!("../tex/fixtures/rgb2x2.png");
+    let both = [Transform::FlipX, Transform::Rot90];
+    let eager = Texture::from_bytes(data).unwrap().with_transforms(&both);
+    let lazy = Texture::lazy("tex/fixtures/rgb2x2.png").unwrap().with_transforms(&both);
+    for &uv in &[(0.0, 0.0), (0.5, 0.0), (0.0, 0.5), (0.5, 0.5)] {
+        assert_eq!(eager.get(uv), lazy.get(uv));
     }
+    assert_eq!(eager.get((0.0, 0.0)), Rgb([255, 255, 255]));
+    assert_eq!(eager.get((0.5, 0.0)), Rgb([0, 255, 0]));
+}
+
+#[test]
+fn test_rgba() {
+    let glass = Texture::from_bytes(include_bytes!("../tex/fixtures/glass2x1.png")).unwrap();
+    assert_eq!(glass.get_rgba((0.0, 0.0)), Rgba([0, 0, 255, 128]));
+    assert_eq!(glass.get_rgba((0.5, 0.0)), Rgba([255, 255, 255, 0]));
+    assert_eq!(glass.get((0.0, 0.0)), Rgb([0, 0, 255]));
+
+    let flipped = Texture::from_bytes(include_bytes!("../tex/fixtures/glass2x1.png")).unwrap()
+        .with_transforms(&[Transform::FlipX]);
+    assert_eq!(flipped.get_rgba((0.0, 0.0)), Rgba([255, 255, 255, 0]));
+
+    let opaque = Texture::from_bytes(include_bytes!("../tex/fixtures/rgb2x2.png")).unwrap();
+    assert_eq!(opaque.get_rgba((0.0, 0.0)), Rgba([255, 0, 0, 255]));
+
+    let deep = Texture::from_bytes(include_bytes!("../tex/fixtures/glass16.png")).unwrap();
+    assert!(deep.has_alpha());
+    assert_eq!(deep.get_rgba((0.0, 0.0)), Rgba([0, 0, 255, 128]));
+}
+
+#[test]
+fn test_dimensions() {
+    let tex = Texture::load("tex/fixtures/rgb64x32.png").unwrap();
+    assert_eq!(tex.dimensions(), (64, 32));
+    assert_eq!((tex.width(), tex.height()), (64, 32));
 
-}
\ No newline at end of file
+    assert_eq!(tex.thumbnail(16).dimensions(), (16, 8));
+    assert_eq!(tex.thumbnail(100).dimensions(), (64, 32));
+}
+
+#[test]
+fn test_from_noise() {
+    let base = Rgb([100, 120, 140]);
+    let texels = |tex: &Texture| tex.image().inner.clone().into_raw();
+
+    let a = Texture::from_noise(7, base, 16, 8);
+    assert_eq!(a.dimensions(), (8, 8));
+    assert_eq!(texels(&a), texels(&Texture::from_noise(7, base, 16, 8)));
+    assert_ne!(texels(&a), texels(&Texture::from_noise(8, base, 16, 8)));
+
+    assert!(a.image().inner.pixels().all(|p| (p[0] as i16 - 100).abs() <= 16 && p[1] - p[0] == 20));
+    assert!(a.image().inner.pixels().any(|p| *p != base));
+    assert!(texels(&Texture::from_noise(7, base, 0, 8)).chunks(3).all(|p| p == base.0));
+
+    let tiny = Texture::from_noise(7, base, 0, 0);
+    assert_eq!(tiny.dimensions(), (1, 1));
+    assert_eq!(tiny.get((0.5, 0.5)), base);
+}
+
+#[cfg(feature = "mipmap")]
+#[test]
+fn test_get_lod_single_texel() {
+    let tex = Texture::load("tex/fixtures/red1x1.png").unwrap();
+    assert_eq!(tex.get_lod((0.5, 0.5), 3), tex.get((0.5, 0.5)));
+}